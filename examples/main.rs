@@ -23,17 +23,17 @@ impl Test {
         self.to_greet = to_greet.to_owned();
     }
 
-    fn to_string(&self) -> String {
+    fn describe(&self) -> String {
         multicall! {
             self:
-            exec return format!(
+            exec format!(
                 "{}, {}! Have some stuff: {}, {}, {}",
                 #.greeting,
                 #.to_greet,
                 #.a,
                 #.b,
                 #.c,
-            );
+            )
         }
     }
 }
@@ -47,11 +47,11 @@ fn main() {
         b = (#.a + value) as i32;
         print_a();
         print_b();
-        exec println!("{}", #.to_string());
+        exec println!("{}", #.describe());
         c = 48;
         set_greeting("Hello");
         set_to_greet("multicall");
-        exec println!("{}", #.to_string());
+        exec println!("{}", #.describe());
         set value = c as u32;
     }
     println!("{}", value);
@@ -1,22 +1,43 @@
 //!
 //! This library provides the [`multicall!`] macro, which allows you to apply multiple operations
-//! to one object without writing the name of the object again and again.
+//! to one object without writing the name of the object again and again. [`with!`] does the
+//! same, but evaluates to the object afterwards.
 //!
 
 #![no_std]
 
 extern crate alloc;
 extern crate proc_macro;
-#[cfg(MULTICALL_DEBUG)]
+#[cfg(feature = "trace")]
 extern crate std;
 
-#[cfg(MULTICALL_DEBUG)]
-use std::println;
-
-use alloc::string::ToString;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
-use proc_macro::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Writes a step of the expansion to stderr, along with the line of the macro call, if the
+/// `trace` feature is enabled. Compiles to nothing otherwise. Steps of an operation use
+/// `in accum at span,`, which puts the operation's state and word count and the position of the
+/// token at `span` in front instead. Steps outside of an operation have no such state.
+macro_rules! trace {
+    (in $accum:ident at $span:expr, $($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        std::eprintln!(
+            "multicall {}:{} [{:?}, {} words]: {}",
+            $span.line(),
+            $span.column(),
+            $accum.state,
+            $accum.words.len(),
+            format_args!($($arg)*),
+        );
+    };
+    ($($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        std::eprintln!("multicall {}: {}", Span::call_site().line(), format_args!($($arg)*));
+    };
+}
 
 /// Macro to execute multiple operations on one object in a short form.
 ///
@@ -37,31 +58,44 @@ use proc_macro::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, Tok
 ///         exec normal_operation(#);
 ///         operation;
 ///         ...
-///     }; // this semicolon is mandatory.
+///     }; // this semicolon is only optional after the last statement.
 /// }
 /// ```
 ///
-/// Evaluates to:
+/// Evaluates to, with `item` standing for the variable the macro keeps the item in:
 /// ```ignore
 /// let mut test_variable = 1;
 /// {
-///     let __multicall_item__ = expr;
-///     __multicall_item__.operation;
-///     test_variable = __multicall_item__.operation;
-///     normal_operation(__multicall_item__);
-///     __multicall_item__.operation;
+///     let item = expr;
+///     item.operation;
+///     test_variable = item.operation;
+///     normal_operation(item);
+///     item.operation;
 ///     ...
 ///     {
-///         let __multicall_item__ = __multicall_item__.subexpr;
-///         __multicall_item__.operation;
-///         test_variable += __multicall_item__.operation;
-///         normal_operation(__multicall_item__);
-///         __multicall_item__.operation;
+///         let item = item.subexpr;
+///         item.operation;
+///         test_variable += item.operation;
+///         normal_operation(item);
+///         item.operation;
 ///         ...
 ///     };
 /// }
 /// ```
 ///
+/// That variable is hygienic, so code in the block can't name it and has to use `#` instead. This
+/// is a breaking change: earlier versions called it `__multicall_item__`, and let `exec`
+/// statements and the macros of `wrap` and `postprocess` write that name to reach the item.
+///
+/// ```compile_fail
+/// use multicall::multicall;
+/// let mut list = Vec::new();
+/// multicall! {
+///     &mut list:
+///     exec __multicall_item__.push(1);
+/// }
+/// ```
+///
 /// Example:
 ///    
 /// ```
@@ -93,169 +127,4856 @@ use proc_macro::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, Tok
 /// }
 /// ```
 ///
-#[proc_macro]
-pub fn multicall(input: TokenStream) -> TokenStream {
-    multicall_internal(input, false, false)
-}
-
-fn multicall_internal(input: TokenStream, is_recursed: bool, mut is_mut: bool) -> TokenStream {
-    let mut iter = input.into_iter();
-    #[cfg(MULTICALL_DEBUG)]
-    println!("creating new multicall block...");
-    let mut dat = if is_recursed {
-        #[cfg(MULTICALL_DEBUG)]
-        println!("inserting multicall item because this is a recursed block.");
-        let mut v = vec![
-            TokenTree::Punct(Punct::new('&', Spacing::Alone)),
-            TokenTree::Ident(Ident::new("mut", Span::call_site())),
-            TokenTree::Ident(Ident::new("__multicall_item__", Span::call_site())),
-            TokenTree::Punct(Punct::new('.', Spacing::Alone)),
-        ];
-        if !is_mut {
-            v.remove(1);
-        }
-        v
-    } else {
-        Vec::new()
-    };
-    #[cfg(MULTICALL_DEBUG)]
-    println!("initialized. reading item...");
-    while let Some(item) = iter.next() {
-        if let TokenTree::Punct(ref x) = item {
-            if x.as_char() == ':' && x.spacing() == Spacing::Alone {
-                break;
-            }
-        }
-        if item.to_string() == "mut" && dat.len() == 1 {
-            is_mut = true;
-        }
-        dat.push(item)
-    }
-    #[cfg(MULTICALL_DEBUG)]
-    println!("item read. writing initial let statement.");
-    let mut ts = TokenStream::new();
-    ts.extend(
-        vec![
-            TokenTree::Ident(Ident::new("let", Span::call_site())),
-            TokenTree::Ident(Ident::new("__multicall_item__", Span::call_site())),
-            TokenTree::Punct(Punct::new('=', Spacing::Alone)),
-        ]
-        .into_iter(),
-    );
-    ts.extend(dat.clone().into_iter());
-    ts.extend(vec![TokenTree::Punct(Punct::new(';', Spacing::Alone))].into_iter());
-    #[cfg(MULTICALL_DEBUG)]
-    println!("done. starting state machine and processing statements.");
-    #[derive(Default, PartialEq, Eq)]
-    enum State {
-        #[default]
-        InsertNew,
-        Set,
-        Inserted,
-    }
-    #[derive(Default)]
-    struct AccumState {
-        words: Vec<TokenTree>,
-        state: State,
-    }
-    ts.extend(
-        iter.fold(AccumState::default(), |mut accum, x| {
-            let o = x.to_string();
-            // Sub-calls
-            if let Some(x) = match x {
-                TokenTree::Group(ref x) if accum.state == State::InsertNew => Some(x),
-                _ => None,
-            } {
-                #[cfg(MULTICALL_DEBUG)]
-                println!("found group, making sub-call:");
-                accum
-                    .words
-                    .extend(multicall_internal(x.stream(), true, is_mut).into_iter());
-                accum.state = State::Inserted;
-                #[cfg(MULTICALL_DEBUG)]
-                println!("sub-call inserted.");
-            // End of call
-            } else if o == ";" {
-                #[cfg(MULTICALL_DEBUG)]
-                println!("found semicolon. resetting.");
-                accum.state = State::InsertNew;
-                accum
-                    .words
-                    .push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
-            // Call content
-            } else {
-                #[cfg(MULTICALL_DEBUG)]
-                println!("found statement. parsing...");
-                if accum.state == State::InsertNew {
-                    #[cfg(MULTICALL_DEBUG)]
-                    println!("detecting statement type...");
-                    if o == "set" {
-                        #[cfg(MULTICALL_DEBUG)]
-                        println!("statement is 'set'.");
-                        accum.state = State::Set;
-                        return accum; // dont insert
-                    } else if o == "exec" {
-                        #[cfg(MULTICALL_DEBUG)]
-                        println!("statement is 'exec'. marking for full replay.");
-                        accum.state = State::Inserted;
-                        return accum; // dont insert
-                    }
-                    #[cfg(MULTICALL_DEBUG)]
-                    println!("inserting item.");
-                    accum.words.push(TokenTree::Ident(Ident::new(
-                        "__multicall_item__",
-                        Span::call_site(),
-                    )));
-                    accum
-                        .words
-                        .push(TokenTree::Punct(Punct::new('.', Spacing::Alone)));
-                    accum.state = State::Inserted;
-                    #[cfg(MULTICALL_DEBUG)]
-                    println!("done. replaying rest.");
-                }
-                if accum.state == State::Set {
-                    if o == "=" {
-                        #[cfg(MULTICALL_DEBUG)]
-                        println!("replaying '='.");
-                        accum.state = State::InsertNew;
-                    }
-                }
-                #[cfg(MULTICALL_DEBUG)]
-                println!("replaying '{x}'");
-                accum
-                    .words
-                    .push(recursive_replace(x, "#", "__multicall_item__"));
-            }
-            accum
-        })
-        .words,
-    );
-    #[cfg(MULTICALL_DEBUG)]
-    println!("multicall block done.");
-    TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, ts)))
-}
-
-fn recursive_replace(token: TokenTree, from: &str, to: &str) -> TokenTree {
-    match token {
-        TokenTree::Group(x) => TokenTree::Group({
-            let mut g = Group::new(
-                x.delimiter(),
-                TokenStream::from_iter(
-                    x.stream()
-                        .into_iter()
-                        .map(|x| recursive_replace(x, from, to)),
-                ),
-            );
-            g.set_span(x.span());
-            g
-        }),
-        TokenTree::Ident(x) => TokenTree::Ident(Ident::new(
-            x.to_string().replace(from, to).as_str(),
-            x.span(),
-        )),
-        TokenTree::Punct(x) if x.as_char() == from.chars().next().unwrap() && from.len() == 1 => {
-            TokenTree::Ident(Ident::new(to, x.span()))
-        }
-        x => x,
+/// Forgetting the `:` after the item, or leaving the item out, is reported as a compile error.
+///
+/// ```compile_fail
+/// use multicall::multicall;
+/// let mut list = Vec::new();
+/// multicall! {
+///     &mut list
+///     push(1);
+/// }
+/// ```
+///
+/// # Statements
+///
+/// Statements start with a keyword like `set` or `exec`, or are operations on the item. A field
+/// of the item may be named like a keyword: if it is followed by an operator, an index or a
+/// call, it is used as a field.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Counters { toggle: u32, reset: [u32; 2], snapshot: i32 }
+///
+/// let mut counters = Counters { toggle: 0, reset: [0; 2], snapshot: 1 };
+/// multicall! {
+///     &mut counters:
+///     toggle += 2;
+///     reset[1] = 7;
+///     snapshot *= -3;
+///     snapshot = #.snapshot - 1;
+/// }
+/// assert_eq!((counters.toggle, counters.reset, counters.snapshot), (2, [0, 7], -4));
+/// ```
+///
+/// ## `set`
+///
+/// `set` assigns to a variable outside of the block. Everything up to the first `=` is the
+/// target, so compound assignments like `+=`, `-=` or `*=` work as well. The value is
+/// prefixed with the item unless it starts with `#`, which parentheses around the value don't
+/// change: `set total = (count + 1);` works like `set total = count + 1;`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Item { weight: u32, count: u32 }
+///
+/// let item = Item { weight: 3, count: 2 };
+/// let mut total = 10;
+/// multicall! {
+///     &item:
+///     set total += weight;
+///     set total -= #.count;
+///     set total *= count;
+/// }
+/// assert_eq!(total, 22);
+/// let (plain, parenthesized);
+/// multicall! {
+///     &item:
+///     set plain = count + 1;
+///     set parenthesized = (count + 1);
+/// }
+/// assert_eq!((plain, parenthesized), (3, 3));
+/// ```
+///
+/// The target can be any place expression, such as a nested field or an index, and may use `#`
+/// as well. Since `HashMap` doesn't implement `IndexMut`, map entries have to be assigned
+/// through `get_mut`, just like outside of the macro.
+///
+/// ```
+/// use multicall::multicall;
+/// use std::collections::HashMap;
+/// struct Item { value: u32, index: usize }
+/// struct Config { field: u32 }
+/// struct Outer { config: Config }
+///
+/// let item = Item { value: 7, index: 1 };
+/// let mut outer = Outer { config: Config { field: 0 } };
+/// let mut list = vec![0; 3];
+/// let mut map = HashMap::from([("key", 0)]);
+/// multicall! {
+///     &item:
+///     set outer.config.field = #.value;
+///     set list[#.index] = value;
+///     set *map.get_mut("key").unwrap() = #.value + 1;
+/// }
+/// assert_eq!(outer.config.field, 7);
+/// assert_eq!(list, [0, 7, 0]);
+/// assert_eq!(map["key"], 8);
+/// ```
+///
+/// The target may also be a tuple, slice or struct pattern, which unpacks the value into several
+/// variables at once. The value of such a `set` is used as written, without the item in front,
+/// so it has to reach the item through `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Clone)]
+/// struct Point { x: i32, y: i32 }
+/// struct Line { start: Point, text: &'static str }
+/// impl Line {
+///     fn split(&self) -> (&'static str, &'static str) { self.text.split_once(' ').unwrap() }
+/// }
+///
+/// let line = Line { start: Point { x: 1, y: 2 }, text: "hello world" };
+/// let (mut first, mut second) = ("", "");
+/// let (mut x, mut y) = (0, 0);
+/// let mut end = 0;
+/// multicall! {
+///     &line:
+///     set (first, second) = #.split();
+///     set Point { x, y } = #.start.clone();
+///     set end = start.x + 5;
+/// }
+/// assert_eq!((first, second), ("hello", "world"));
+/// assert_eq!((x, y), (1, 2));
+/// assert_eq!(end, 6);
+/// let (left, right) = (10, 20);
+/// multicall! {
+///     &line.start:
+///     set (x, y) = (#.y, #.x);
+///     set [first, second] = ["a", "b"];
+///     set (end, _) = (left, right);
+/// }
+/// assert_eq!((x, y, first, second, end), (2, 1, "a", "b", 10));
+/// ```
+///
+/// `set let name = value;` binds the value to a new local variable instead, which later
+/// statements of the same block can use. The binding keeps its span, so the compiler's
+/// `unused_variables` lint reports it if it is never used, and `_name` or `#[allow]` silence it
+/// as usual.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Item { price: u32, count: u32 }
+///
+/// let item = Item { price: 3, count: 4 };
+/// let mut total = 0;
+/// multicall! {
+///     &item:
+///     set let price = price;
+///     set total = count * price;
+/// }
+/// assert_eq!(total, 12);
+/// ```
+///
+/// ```compile_fail
+/// #![deny(unused_variables)]
+/// use multicall::multicall;
+/// struct Item { price: u32, count: u32 }
+///
+/// fn main() {
+///     let item = Item { price: 3, count: 4 };
+///     let mut total = 0;
+///     multicall! {
+///         &item:
+///         set let price = price;
+///         set total = count;
+///     }
+///     assert_eq!(total, 4);
+/// }
+/// ```
+///
+/// ## `exec`
+///
+/// `exec` runs a statement as written, without prefixing it with the item. Every `#` in it,
+/// including inside nested groups, is replaced by the item. This happens before any macros in
+/// the statement are expanded, so other macros receive the item in place of `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Point { x: i32, y: i32 }
+/// macro_rules! sum {
+///     ($a:expr, $b:expr) => { $a + $b };
+/// }
+/// macro_rules! source {
+///     (of $e:expr) => { stringify!($e) };
+/// }
+///
+/// let point = Point { x: 1, y: 2 };
+/// let (total, text);
+/// multicall! {
+///     &point:
+///     exec total = sum!(#.x, #.y * 10);
+///     exec text = source!(of #.y);
+/// }
+/// assert_eq!(total, 21);
+/// assert_eq!(text, "__multicall_item__.y");
+/// ```
+///
+/// The statements aren't put into a closure, so `?` and `return` in an `exec`, or anywhere else,
+/// leave the function the macro is used in, sub-blocks included. `try operation;` is short for
+/// `operation?;`, and may use `#` like any operation.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Counter { value: u8 }
+/// impl Counter {
+///     fn bump(&mut self, by: u8) -> Result<u8, String> {
+///         self.value = self.value.checked_add(by).ok_or("overflow")?;
+///         Ok(self.value)
+///     }
+/// }
+///
+/// fn bump_all(counter: &mut Counter, steps: &[u8]) -> Result<u8, String> {
+///     multicall! {
+///         &mut *counter:
+///         exec if steps.is_empty() { return Ok(0) };
+///         exec #.bump(steps[0])?;
+///         try bump(steps[1]);
+///         try #.bump(#.value);
+///     }
+///     Ok(counter.value)
+/// }
+///
+/// assert_eq!(bump_all(&mut Counter { value: 0 }, &[]), Ok(0));
+/// assert_eq!(bump_all(&mut Counter { value: 0 }, &[1, 2]), Ok(6));
+/// assert_eq!(bump_all(&mut Counter { value: 0 }, &[200, 100]), Err("overflow".into()));
+/// ```
+///
+/// `#ref` and `#mut` stand for `&#` and `&mut #`, for functions that take the item by reference.
+/// An owned item is bound mutably if a statement uses `#mut` on it.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Default)]
+/// struct Registry { names: Vec<&'static str> }
+/// fn register(registry: &mut Registry) { registry.names.push("plugin") }
+/// fn inspect(registry: &Registry) -> usize { registry.names.len() }
+///
+/// let mut registry = Registry::default();
+/// let count;
+/// multicall! {
+///     &mut registry:
+///     exec register(#mut);
+///     exec count = inspect(#ref);
+/// }
+/// assert_eq!((count, registry.names.len()), (1, 1));
+/// let registry = multicall! {
+///     Registry::default():
+///     exec register(#mut);
+///     exec register(#mut);
+///     yield;
+/// };
+/// assert_eq!(inspect(&registry), 2);
+/// ```
+///
+/// A `##` stands for a literal `#`, which leaves attributes and macros that expect a `#` intact.
+///
+/// ```
+/// use multicall::multicall;
+/// macro_rules! tag {
+///     ($list:expr, #[$tag:ident]) => { $list.push(stringify!($tag)) };
+/// }
+///
+/// let mut tags = Vec::new();
+/// multicall! {
+///     &mut tags:
+///     exec tag!(#, ##[first]);
+///     exec { ##[allow(unused_variables)] let unused = #.len(); };
+///     exec tag!(#, ##[second]);
+/// }
+/// assert_eq!(tags, ["first", "second"]);
+/// ```
+///
+/// `raw` runs a statement exactly as written: unlike `exec`, it leaves every `#` in it alone.
+///
+/// ```
+/// use multicall::multicall;
+/// fn compute() -> usize { 3 }
+///
+/// let mut list = vec![1, 2];
+/// let (count, text);
+/// multicall! {
+///     &mut list:
+///     raw let base = compute();
+///     push(base);
+///     raw text = stringify!(#);
+///     exec count = #.len() + base;
+/// }
+/// assert_eq!((count, text), (6, "#"));
+/// assert_eq!(list, [1, 2, 3]);
+/// ```
+///
+/// In closures and nested blocks, `#` still means the item of the statement's own block, even
+/// where a binding of the same name is declared in between. The item's variable is hygienic, so
+/// nothing written in the macro can shadow it. This also holds for a nested `multicall!`, where
+/// `#` means the outer item and `##` the nested one's.
+///
+/// ```
+/// use multicall::multicall;
+/// let other = vec![1, 2, 3];
+/// let mut list = vec![10, 20];
+/// let (sums, shadowed, outer, inner);
+/// multicall! {
+///     &mut list:
+///     exec sums = other.iter().map(|x| x + #[0]).collect::<Vec<_>>();
+///     exec shadowed = { let __multicall_item__ = &other; #.len() };
+///     exec outer = multicall! { &other: exec #.len() };
+///     exec inner = multicall! { &other: exec ##.len() };
+///     { [1]: exec *# += other.iter().map(|x| x * *#).sum::<i32>(); };
+/// }
+/// assert_eq!(sums, [11, 12, 13]);
+/// assert_eq!((shadowed, outer, inner), (2, 2, 3));
+/// assert_eq!(list, [10, 140]);
+/// ```
+///
+/// ## `let`
+///
+/// `let` statements are written as is, with `#` standing for the item, like `exec`. The variable
+/// they declare can be used by the statements after them, and goes away with the block.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Rect { width: u32, height: u32, area: u32 }
+///
+/// let mut rect = Rect { width: 3, height: 4, area: 0 };
+/// let perimeter;
+/// multicall! {
+///     &mut rect:
+///     let sum = #.width + #.height;
+///     area = #.width * #.height;
+///     set perimeter = height + #.width + sum;
+///     width += sum;
+/// }
+/// assert_eq!((rect.area, perimeter, rect.width), (12, 14, 10));
+/// ```
+///
+/// ## Indexed assignments
+///
+/// An index in the target of an assignment may read the item through `#`, as in
+/// `items[#.len() - 1] = value;`. Such indices are computed into a temporary before the
+/// assignment, so they don't conflict with the mutable borrow of the target.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Table { cells: Vec<u32>, cursor: usize, value: u32 }
+/// impl Table {
+///     fn last(&self) -> usize { self.cells.len() - 1 }
+/// }
+///
+/// let mut table = Table { cells: vec![0; 4], cursor: 1, value: 7 };
+/// multicall! {
+///     &mut table:
+///     cells[#.cursor] = #.value;
+///     cells[#.last()] = #.value * 2;
+///     cells[#.cells.len() - 2] = 5;
+/// }
+/// assert_eq!(table.cells, [0, 7, 5, 14]);
+/// ```
+///
+/// A statement starting with an index works on the item itself, so `[0] = 5;` assigns to
+/// `item[0]`, and `[row][column] = value;` to an element of a nested collection.
+///
+/// ```
+/// use multicall::multicall;
+/// use std::collections::HashMap;
+/// struct Sheet { cells: Vec<Vec<u32>>, names: HashMap<&'static str, Vec<u32>> }
+///
+/// let names = HashMap::from([("a", vec![1, 2])]);
+/// let mut sheet = Sheet { cells: vec![vec![0; 2]; 2], names };
+/// multicall! {
+///     &mut sheet:
+///     { cells: [0][1] = 5; [1] = vec![3, 4]; [1][0] += 1; };
+///     { names: exec #.insert("b", vec![]); };
+/// }
+/// let count = multicall! { &sheet.names: ["a"].len() };
+/// assert_eq!(sheet.cells, [[0, 5], [4, 4]]);
+/// assert_eq!((count, sheet.names["b"].len()), (2, 0));
+/// ```
+///
+/// ## Closures
+///
+/// `#` is replaced inside closures as well. A `?` in a closure returns from the closure, so it
+/// works with methods like `try_for_each` without affecting the surrounding function.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Batch { items: Vec<u32>, limit: u32, checked: u32 }
+/// fn check(item: u32, limit: u32) -> Result<(), String> {
+///     if item > limit { Err(format!("{item} is over {limit}")) } else { Ok(()) }
+/// }
+///
+/// fn validate(batch: &mut Batch) -> Result<(), String> {
+///     let result;
+///     multicall! {
+///         &mut *batch:
+///         set result = items.iter().try_for_each(|&item| {
+///             check(item, #.limit)?;
+///             #.checked += 1;
+///             Ok(())
+///         });
+///     }
+///     result
+/// }
+///
+/// let mut batch = Batch { items: vec![1, 5, 20, 3], limit: 10, checked: 0 };
+/// assert_eq!(validate(&mut batch), Err("20 is over 10".into()));
+/// assert_eq!(batch.checked, 2);
+/// batch.items.pop();
+/// batch.items[2] = 2;
+/// batch.limit = 20;
+/// assert_eq!(validate(&mut batch), Ok(()));
+/// assert_eq!(batch.checked, 5);
+/// ```
+///
+/// ## `set_some`
+///
+/// `set_some target = value;` works like `set`, but expects the value to be an `Option` and
+/// only assigns if it is `Some`, leaving the target unchanged otherwise.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Overrides { name: Option<&'static str>, port: Option<u16> }
+///
+/// let overrides = Overrides { name: Some("server"), port: None };
+/// let mut name = "default";
+/// let mut port = 80;
+/// multicall! {
+///     &overrides:
+///     set_some name = name;
+///     set_some port = #.port;
+/// }
+/// assert_eq!((name, port), ("server", 80));
+///
+/// {
+///     #[allow(dead_code)]
+///     enum Setting { Some, None }
+///     use Setting::*;
+///     multicall! {
+///         &overrides:
+///         set_some name = #.name.map(|_| "renamed");
+///     }
+/// }
+/// assert_eq!(name, "renamed");
+/// ```
+///
+/// ## `match_set`
+///
+/// `match_set target = { arms } on scrutinee;` assigns the result of matching `scrutinee`
+/// against the arms to an outer variable. Like the value of a `set`, the scrutinee is prefixed
+/// with the item unless it starts with `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// enum Status { Ready, Busy(u32), Failed }
+/// struct Worker { status: Status }
+///
+/// let mut labels = Vec::new();
+/// for status in [Status::Ready, Status::Busy(3), Status::Failed] {
+///     let worker = Worker { status };
+///     let label;
+///     multicall! {
+///         &worker:
+///         match_set label = {
+///             Status::Ready => "ready",
+///             Status::Busy(n) if n > 1 => "very busy",
+///             Status::Busy(_) => "busy",
+///             Status::Failed => "failed",
+///         } on #.status;
+///     }
+///     labels.push(label);
+/// }
+/// assert_eq!(labels, ["ready", "very busy", "failed"]);
+/// ```
+///
+/// ## `map_err`
+///
+/// A fallible statement can convert its error before propagating it by ending in
+/// `? map_err(converter)`, which becomes `operation.map_err(converter)?`.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Debug, PartialEq)]
+/// enum ConfigError { BadPort(String) }
+/// struct Config { port: u16 }
+/// impl Config {
+///     fn set_port(&mut self, port: &str) -> Result<(), String> {
+///         self.port = port.parse().map_err(|_| port.to_owned())?;
+///         Ok(())
+///     }
+/// }
+///
+/// fn configure(config: &mut Config, port: &str) -> Result<(), ConfigError> {
+///     multicall! {
+///         &mut *config:
+///         set_port(port)? map_err(ConfigError::BadPort);
+///     }
+///     Ok(())
+/// }
+///
+/// let mut config = Config { port: 0 };
+/// assert_eq!(configure(&mut config, "8080"), Ok(()));
+/// assert_eq!(config.port, 8080);
+/// assert_eq!(configure(&mut config, "http"), Err(ConfigError::BadPort("http".into())));
+/// ```
+///
+/// ## `toggle`
+///
+/// `toggle field;` negates a `bool` field of the item, which may also be nested or use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Panel { visible: bool }
+/// struct Window { maximized: bool, panel: Panel }
+///
+/// let mut window = Window { maximized: false, panel: Panel { visible: true } };
+/// multicall! {
+///     &mut window:
+///     toggle maximized;
+///     toggle panel.visible;
+///     toggle #.maximized;
+/// }
+/// assert!(!window.maximized);
+/// assert!(!window.panel.visible);
+/// ```
+///
+/// ## `retry`
+///
+/// `retry count operation;` runs an operation returning a `Result` until it succeeds, at most
+/// `count` times. If all attempts fail, the last error is propagated with `?`. The count is a
+/// literal, a variable or an expression in parentheses, which may use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Connection { failures: u32, attempts: u32, max_attempts: u32 }
+/// impl Connection {
+///     fn connect(&mut self) -> Result<(), String> {
+///         self.attempts += 1;
+///         match self.attempts > self.failures {
+///             true => Ok(()),
+///             false => Err(format!("attempt {} failed", self.attempts)),
+///         }
+///     }
+/// }
+///
+/// fn open(connection: &mut Connection) -> Result<u32, String> {
+///     multicall! {
+///         &mut *connection:
+///         retry 3 connect();
+///         retry (#.max_attempts) connect();
+///         exec Ok(#.attempts)
+///     }
+/// }
+///
+/// let mut flaky = Connection { failures: 2, attempts: 0, max_attempts: 1 };
+/// assert_eq!(open(&mut flaky), Ok(4));
+/// let mut broken = Connection { failures: 5, attempts: 0, max_attempts: 1 };
+/// assert_eq!(open(&mut broken), Err("attempt 3 failed".into()));
+///
+/// fn open_shadowed(connection: &mut Connection) -> Result<u32, String> {
+///     #[allow(dead_code)]
+///     enum Reply { Ok, Err }
+///     use Reply::*;
+///     multicall! {
+///         &mut *connection:
+///         retry 3 connect();
+///         exec Result::Ok(#.attempts)
+///     }
+/// }
+/// let mut flaky = Connection { failures: 1, attempts: 0, max_attempts: 1 };
+/// assert_eq!(open_shadowed(&mut flaky), Ok(2));
+/// ```
+///
+/// ## `take`
+///
+/// `take method(args);` calls a method that takes the item by value, like the methods of many
+/// builders, and assigns the result back to the item. The item then has to be owned, but a
+/// sub-block on one of its fields can use `take` on that field as well.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Default)]
+/// struct Body { text: String }
+/// impl Body {
+///     fn text(self, text: &str) -> Self { Body { text: text.into() } }
+/// }
+/// #[derive(Default)]
+/// struct Request { url: String, headers: Vec<String>, body: Body }
+/// impl Request {
+///     fn url(self, url: &str) -> Self { Request { url: url.into(), ..self } }
+///     fn header(mut self, header: &str) -> Self { self.headers.push(header.into()); self }
+/// }
+///
+/// let request = multicall! {
+///     Request::default():
+///     take url("/");
+///     take header("accept");
+///     {
+///         body:
+///         take text("hello");
+///     };
+///     headers.push("host".into());
+///     yield;
+/// };
+/// assert_eq!((request.url.as_str(), request.headers.len()), ("/", 2));
+/// assert_eq!(request.body.text, "hello");
+/// ```
+///
+/// ## `drop`
+///
+/// `drop method(args);` discards the result of an operation explicitly, so it doesn't trigger
+/// the `unused_must_use` lint. Unlike `take`, the item stays as it is.
+///
+/// ```
+/// #![deny(unused_must_use)]
+/// use multicall::multicall;
+/// struct Cache { entries: Vec<u32> }
+/// impl Cache {
+///     #[must_use]
+///     fn insert(&mut self, entry: u32) -> bool {
+///         self.entries.push(entry);
+///         self.entries.len() == 1
+///     }
+/// }
+///
+/// let mut cache = Cache { entries: Vec::new() };
+/// multicall! {
+///     &mut cache:
+///     drop insert(1);
+///     drop #.insert(2);
+/// }
+/// assert_eq!(cache.entries, [1, 2]);
+/// ```
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// use multicall::multicall;
+/// struct Cache { entries: Vec<u32> }
+/// impl Cache {
+///     #[must_use]
+///     fn insert(&mut self, entry: u32) -> bool {
+///         self.entries.push(entry);
+///         self.entries.len() == 1
+///     }
+/// }
+///
+/// let mut cache = Cache { entries: Vec::new() };
+/// multicall! {
+///     &mut cache:
+///     insert(1);
+/// }
+/// ```
+///
+/// ## `|>`
+///
+/// `|> function(args);` passes the item by value to a free function, as its first argument, and
+/// assigns the result back to the item. Several of them can be chained in one statement. Like for
+/// `take`, the item has to be owned, and since it is moved into the call, the other arguments
+/// can't use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// fn fill(mut list: Vec<i32>, count: i32) -> Vec<i32> {
+///     list.extend((0..count).rev());
+///     list
+/// }
+/// fn sorted(mut list: Vec<i32>) -> Vec<i32> {
+///     list.sort();
+///     list
+/// }
+/// fn clamped(list: Vec<i32>, min: i32, max: i32) -> Vec<i32> {
+///     list.into_iter().map(|x| x.clamp(min, max)).collect()
+/// }
+///
+/// let list = multicall! {
+///     Vec::new():
+///     |> fill(4) |> sorted();
+///     push(9);
+///     |> clamped(1, 5);
+///     yield;
+/// };
+/// assert_eq!(list, [1, 1, 2, 3, 5]);
+/// ```
+///
+/// ## `inc` and `dec`
+///
+/// `inc field;` and `dec field;` add or subtract one from a numeric field of the item.
+/// `inc field by step;` uses a different step, which may use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Stats { hits: u32, misses: i64, step: u32 }
+///
+/// let mut stats = Stats { hits: 0, misses: 10, step: 5 };
+/// multicall! {
+///     &mut stats:
+///     inc hits;
+///     inc hits by #.step * 2;
+///     dec misses;
+///     dec misses by 4;
+/// }
+/// assert_eq!((stats.hits, stats.misses), (11, 5));
+/// ```
+///
+/// Fields named `inc`, `dec`, `retry` or `check` followed by an operator are used as fields.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Limits { inc: u32, dec: i32, retry: u32, check: u32 }
+///
+/// let mut limits = Limits { inc: 1, dec: 0, retry: 0, check: 5 };
+/// multicall! {
+///     &mut limits:
+///     inc += 2;
+///     dec -= 1;
+///     retry += 3;
+///     check += 5;
+///     inc inc;
+/// }
+/// assert_eq!((limits.inc, limits.dec, limits.retry, limits.check), (4, -1, 3, 10));
+/// ```
+///
+/// ## `if` and `else`
+///
+/// `if condition { ... } else { ... };` runs the statements in the braces on the item depending on
+/// the condition, which is written as is, with `#` standing for the item. `else if` chains and
+/// `if let` work as well.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Player { health: u32, shield: Option<u32>, alive: bool }
+///
+/// fn hit(player: &mut Player, damage: u32) {
+///     multicall! {
+///         &mut *player:
+///         if let Some(shield) = #.shield {
+///             shield = Some(shield.saturating_sub(damage)).filter(|&x| x > 0);
+///         } else if #.health > damage {
+///             health -= damage;
+///         } else {
+///             health = 0;
+///             alive = false;
+///         };
+///     }
+/// }
+///
+/// let mut player = Player { health: 10, shield: Some(5), alive: true };
+/// hit(&mut player, 5);
+/// assert_eq!((player.shield, player.health), (None, 10));
+/// hit(&mut player, 4);
+/// assert_eq!(player.health, 6);
+/// hit(&mut player, 8);
+/// assert_eq!((player.health, player.alive), (0, false));
+/// ```
+///
+/// ## `match`
+///
+/// `match scrutinee { Pattern => operation, Pattern => { ... } };` runs the operation or the
+/// statements in the braces of the matching arm on the item. Like the condition of an `if`, the
+/// scrutinee is written as is, with `#` standing for the item, and so are the patterns and their
+/// guards.
+///
+/// ```
+/// use multicall::multicall;
+/// enum Kind { Circle, Square, Line }
+/// struct Shape { kind: Kind, sides: u32, size: u32, rounded: bool }
+/// impl Shape {
+///     fn set_sides(&mut self, sides: u32) { self.sides = sides }
+/// }
+///
+/// fn describe(shape: &mut Shape) {
+///     multicall! {
+///         &mut *shape:
+///         match #.kind {
+///             Kind::Circle => {
+///                 set_sides(0);
+///                 rounded = true;
+///             }
+///             Kind::Square if #.size > 10 => set_sides(40),
+///             Kind::Square | Kind::Line => set_sides(4),
+///         };
+///     }
+/// }
+///
+/// let shape = |kind, size| Shape { kind, sides: 1, size, rounded: false };
+/// let mut circle = shape(Kind::Circle, 1);
+/// describe(&mut circle);
+/// assert_eq!((circle.sides, circle.rounded), (0, true));
+/// let mut square = shape(Kind::Square, 1);
+/// let mut big = shape(Kind::Square, 20);
+/// let mut line = shape(Kind::Line, 1);
+/// describe(&mut square);
+/// describe(&mut big);
+/// describe(&mut line);
+/// assert_eq!((square.sides, big.sides, line.sides), (4, 40, 4));
+/// ```
+///
+/// ## `for`
+///
+/// `for element in iter { ... };` runs the statements in the braces on every element, in place of
+/// the item. The iterator is written as is, with `#` standing for the item, and `break` and
+/// `continue` work like in any loop. To still reach the item inside the loop, give it a name with
+/// `item as name:`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Task { done: bool, owner: u32 }
+/// struct Project { id: u32, tasks: Vec<Task> }
+///
+/// let task = |done| Task { done, owner: 0 };
+/// let mut project = Project { id: 7, tasks: vec![task(false), task(true), task(false), task(false)] };
+/// multicall! {
+///     &mut project as project:
+///     for task in #.tasks.iter_mut().take(3) {
+///         if #.done {
+///             continue;
+///         };
+///         owner = project.id;
+///     };
+/// }
+/// let owners: Vec<u32> = project.tasks.iter().map(|x| x.owner).collect();
+/// assert_eq!(owners, [7, 0, 7, 0]);
+/// ```
+///
+/// ## `repeat`
+///
+/// `repeat count { ... };` runs the statements in the braces `count` times. The count may use
+/// `#`, and if it is a constant like a const generic parameter, the compiler is free to unroll
+/// the loop.
+///
+/// ```
+/// use multicall::multicall;
+/// fn pad<const N: usize>(list: &mut Vec<u32>) {
+///     multicall! {
+///         &mut *list:
+///         repeat N {
+///             push(0);
+///         };
+///         repeat (#.len() / 2) {
+///             push(1);
+///         };
+///     }
+/// }
+///
+/// let mut list = Vec::new();
+/// pad::<4>(&mut list);
+/// assert_eq!(list, [0, 0, 0, 0, 1, 1]);
+/// ```
+///
+/// ## `batch`
+///
+/// `batch { ... };` runs the assignments in the braces as one update: all assigned values are
+/// evaluated first, so values that use `#` see the item as it was before the batch.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Fibonacci { previous: u64, current: u64 }
+///
+/// let mut fib = Fibonacci { previous: 0, current: 1 };
+/// multicall! {
+///     &mut fib:
+///     repeat 5 {
+///         batch {
+///             previous = #.current;
+///             current += #.previous;
+///         };
+///     };
+///     batch {
+///         current <<= #.previous;
+///         previous = #.current;
+///     };
+/// }
+/// assert_eq!((fib.previous, fib.current), (8, 256));
+/// ```
+///
+/// ## `snapshot`
+///
+/// `snapshot history;` pushes a clone of the item to the outer collection `history`, recording its
+/// state at that point of the block. The item has to implement [`Clone`].
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Clone, Debug, PartialEq)]
+/// struct Counter { value: u32 }
+///
+/// let mut counter = Counter { value: 0 };
+/// let mut history = Vec::new();
+/// multicall! {
+///     &mut counter:
+///     snapshot history;
+///     value += 1;
+///     snapshot history;
+///     value *= 10;
+///     snapshot history;
+/// }
+/// let values: Vec<u32> = history.iter().map(|x| x.value).collect();
+/// assert_eq!(values, [0, 1, 10]);
+/// ```
+///
+/// ## `share`
+///
+/// `share into handles;` pushes a new handle to the item, which is usually an [`Rc`] or an
+/// [`Arc`], to the outer collection `handles`. This clones the pointer, not the value behind it.
+///
+/// [`Rc`]: https://doc.rust-lang.org/std/rc/struct.Rc.html
+/// [`Arc`]: https://doc.rust-lang.org/std/sync/struct.Arc.html
+///
+/// ```
+/// use multicall::multicall;
+/// use std::{cell::RefCell, rc::Rc};
+/// struct Config { level: u8 }
+///
+/// let config = Rc::new(RefCell::new(Config { level: 0 }));
+/// let mut consumers = Vec::new();
+/// multicall! {
+///     &config:
+///     borrow_mut().level = 3;
+///     share into consumers;
+///     share into consumers;
+/// }
+/// assert_eq!(Rc::strong_count(&config), 3);
+/// assert!(Rc::ptr_eq(&consumers[0], &config));
+/// assert_eq!(consumers[1].borrow().level, 3);
+///
+/// let values = Rc::new(vec![1, 2]);
+/// let mut readers = Vec::new();
+/// multicall! {
+///     Rc::clone(&values):
+///     share into readers;
+/// }
+/// assert_eq!(Rc::strong_count(&values), 2);
+/// assert_eq!(*readers[0], [1, 2]);
+/// ```
+///
+/// ## `when_variant`
+///
+/// `when_variant Pattern { ... };` runs the statements in the braces only if the item, usually an
+/// enum, matches the pattern. The statements still work on the whole item, and may use the
+/// bindings of the pattern as long as they don't also change the item.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Debug, PartialEq)]
+/// enum Job { Queued(u32), Running { progress: u32 }, Done }
+/// impl Job {
+///     fn advance(&mut self) {
+///         *self = match *self {
+///             Job::Queued(_) => Job::Running { progress: 0 },
+///             Job::Running { progress } if progress >= 50 => Job::Done,
+///             Job::Running { progress } => Job::Running { progress: progress + 50 },
+///             Job::Done => Job::Done,
+///         };
+///     }
+/// }
+///
+/// let mut log = Vec::new();
+/// for mut job in [Job::Queued(1), Job::Running { progress: 50 }, Job::Done] {
+///     multicall! {
+///         &mut job:
+///         when_variant Job::Queued(id) {
+///             exec log.push(*id);
+///             advance();
+///         };
+///         when_variant Job::Running { .. } {
+///             advance();
+///         };
+///     }
+///     log.push(match job { Job::Queued(_) => 10, Job::Running { progress } => progress, Job::Done => 99 });
+/// }
+/// assert_eq!(log, [1, 50, 99, 99]);
+/// ```
+///
+/// ## `if_debug`
+///
+/// `if_debug { ... };` runs the statements in the braces only if the type of the item
+/// implements [`Debug`](core::fmt::Debug). Inside, `#` is the item as a `&dyn Debug`. The check
+/// happens where the macro is used, so in a generic function it depends on the bounds of the
+/// type parameter rather than on the actual type.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Debug)]
+/// struct Visible { id: u32 }
+/// struct Hidden { id: u32 }
+///
+/// let mut log = Vec::new();
+/// multicall! {
+///     &Visible { id: 1 }:
+///     if_debug {
+///         exec log.push(format!("{:?}", #));
+///     };
+///     exec log.push(#.id.to_string());
+/// }
+/// multicall! {
+///     &Hidden { id: 2 }:
+///     if_debug {
+///         exec log.push(format!("{:?}", #));
+///     };
+///     exec log.push(#.id.to_string());
+/// }
+/// multicall! {
+///     Visible { id: 3 }:
+///     if_debug {
+///         exec log.push(format!("{:?}", #));
+///     };
+/// }
+/// multicall! {
+///     Hidden { id: 4 }:
+///     if_debug {
+///         exec log.push(format!("{:?}", #));
+///     };
+/// }
+/// assert_eq!(log, ["Visible { id: 1 }", "1", "2", "Visible { id: 3 }"]);
+/// ```
+///
+/// ## `extend` and `push_all`
+///
+/// `extend field from iter;` extends a collection field of the item, and
+/// `push_all field: [a, b, c];` pushes several values to it one by one. The values may use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Inventory { items: Vec<u32>, restock: Vec<u32>, log: Vec<u32> }
+///
+/// let mut inventory = Inventory { items: vec![1], restock: vec![5, 6], log: Vec::new() };
+/// multicall! {
+///     &mut inventory:
+///     push_all items: [2, 3, #.items[0] * 10];
+///     extend items from #.restock.drain(..);
+///     extend log from 0..2;
+/// }
+/// assert_eq!(inventory.items, [1, 2, 3, 10, 5, 6]);
+/// assert!(inventory.restock.is_empty());
+/// assert_eq!(inventory.log, [0, 1]);
+/// ```
+///
+/// ## `assign`
+///
+/// `assign a, b, c from source;` copies the listed fields of `source` into the fields of the same
+/// name of the item. The source may use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Style { color: u32, width: f32, label: String }
+/// struct Shape { color: u32, width: f32, label: String, sides: u8 }
+///
+/// let theme = Style { color: 0xff0000, width: 2.5, label: "warning".to_string() };
+/// let mut shape = Shape { color: 0, width: 1.0, label: String::new(), sides: 3 };
+/// multicall! {
+///     &mut shape:
+///     assign color, width, label from theme;
+/// }
+/// assert_eq!((shape.color, shape.width, shape.label.as_str()), (0xff0000, 2.5, "warning"));
+/// assert_eq!(shape.sides, 3);
+/// ```
+///
+/// ## `call`
+///
+/// `call method;` calls a method of the item without arguments, adding the `()`. This also makes
+/// it clear that a method is meant when the item has a field of the same name. `call method(..);`
+/// is the same as just `method(..);`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Report { lines: Vec<String>, title: String }
+/// impl Report {
+///     fn title(&mut self) { self.lines.insert(0, self.title.to_uppercase()); }
+///     fn line(&mut self, text: &str) { self.lines.push(text.into()); }
+/// }
+///
+/// let mut report = Report { lines: Vec::new(), title: "summary".into() };
+/// multicall! {
+///     &mut report:
+///     call line("all good");
+///     call title;
+/// }
+/// assert_eq!(report.lines, ["SUMMARY", "all good"]);
+/// ```
+///
+/// ## `return_if`
+///
+/// `return_if condition: value;` makes the block evaluate to `value` right away if the condition
+/// holds, skipping the statements after it. Like the value of a `set`, the condition is prefixed
+/// with the item unless it starts with `#`, and the value may use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Cache { hits: u32, entries: Vec<(u32, &'static str)> }
+///
+/// fn lookup(cache: &mut Cache, key: u32) -> &'static str {
+///     multicall! {
+///         &mut *cache:
+///         hits += 1;
+///         return_if entries.is_empty(): "empty";
+///         return_if #.entries[0].0 == key: #.entries[0].1;
+///         hits -= 1;
+///         exec "missed"
+///     }
+/// }
+///
+/// let mut cache = Cache { hits: 0, entries: Vec::new() };
+/// assert_eq!(lookup(&mut cache, 1), "empty");
+/// cache.entries.push((1, "one"));
+/// assert_eq!(lookup(&mut cache, 1), "one");
+/// assert_eq!(lookup(&mut cache, 2), "missed");
+/// assert_eq!(cache.hits, 2);
+/// ```
+///
+/// In a sub-block, `return_if` only skips the rest of the sub-block.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Child { n: u32 }
+/// struct Parent { child: Child, m: u32 }
+///
+/// fn next(parent: &mut Parent) -> u32 {
+///     multicall! {
+///         &mut *parent:
+///         return_if m == 0: 0;
+///         {
+///             child:
+///             return_if n >= 2: 10;
+///             n += 1;
+///             exec #.n
+///         }
+///     }
+/// }
+///
+/// let mut parent = Parent { child: Child { n: 1 }, m: 1 };
+/// assert_eq!((next(&mut parent), next(&mut parent)), (2, 10));
+/// parent.m = 0;
+/// assert_eq!(next(&mut parent), 0);
+/// ```
+///
+/// ## `require`
+///
+/// `require condition else error;` returns `Err(error.into())` from the surrounding function
+/// unless the condition holds. Like the value of a `set`, the condition is prefixed with the item
+/// unless it starts with `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Debug, PartialEq)]
+/// enum OrderError { Empty, TooLarge(usize) }
+/// #[derive(Debug, PartialEq)]
+/// struct AppError(OrderError);
+/// impl From<OrderError> for AppError {
+///     fn from(error: OrderError) -> Self { AppError(error) }
+/// }
+///
+/// fn submit(order: &mut Vec<u32>) -> Result<u32, AppError> {
+///     multicall! {
+///         &mut *order:
+///         require len() > 0 else OrderError::Empty;
+///         require #.len() <= 3 else OrderError::TooLarge(#.len());
+///         sort();
+///     }
+///     Ok(order.iter().sum())
+/// }
+///
+/// assert_eq!(submit(&mut vec![3, 1]), Ok(4));
+/// assert_eq!(submit(&mut vec![]), Err(AppError(OrderError::Empty)));
+/// assert_eq!(submit(&mut vec![1; 5]), Err(AppError(OrderError::TooLarge(5))));
+/// ```
+///
+/// ## `ensure_range`
+///
+/// `ensure_range field in lo..=hi clamp;` clamps a numeric field of the item to the range, and
+/// `ensure_range field in range else error;` returns the error like `require` unless the field is
+/// in the range. The bounds may use `#`.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Volume { level: i32, balance: f32 }
+///
+/// fn adjust(volume: &mut Volume, level: i32) -> Result<(), String> {
+///     multicall! {
+///         &mut *volume:
+///         level = level;
+///         ensure_range level in 0..=100 clamp;
+///         ensure_range balance in -1.0..=1.0 else format!("balance {} is off", #.balance);
+///     }
+///     Ok(())
+/// }
+///
+/// let mut volume = Volume { level: 0, balance: 0.5 };
+/// assert_eq!(adjust(&mut volume, 150), Ok(()));
+/// assert_eq!(volume.level, 100);
+/// volume.balance = 2.0;
+/// assert_eq!(adjust(&mut volume, -5), Err("balance 2 is off".to_string()));
+/// assert_eq!(volume.level, 0);
+/// ```
+///
+/// ## `rebuild`
+///
+/// `rebuild |old| value;` replaces the item by `value`, which can use the previous item as `old`,
+/// like `Type { field: x, ..old }`. The item has to be owned, not a reference, and is moved into
+/// `old`.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Debug, PartialEq)]
+/// struct Request { url: String, retries: u32, timeout: u32 }
+///
+/// let request = multicall! {
+///     Request { url: "/".into(), retries: 0, timeout: 30 }:
+///     rebuild |old| Request { retries: 3, ..old };
+///     rebuild |request| Request { timeout: request.timeout * 2, ..request };
+///     yield;
+/// };
+/// assert_eq!(request, Request { url: "/".into(), retries: 3, timeout: 60 });
+/// ```
+///
+/// Since the item is assigned, not shadowed, `rebuild` works inside `if`, `repeat` and the like.
+///
+/// ```
+/// use multicall::with;
+/// let text = |flag| with! {
+///     String::from("a"):
+///     if flag {
+///         rebuild |old| old + "b";
+///     };
+///     repeat 2 {
+///         rebuild |old| old + "c";
+///     };
+/// };
+/// assert_eq!((text(true), text(false)), ("abcc".to_string(), "acc".to_string()));
+/// ```
+///
+/// ## `reset`
+///
+/// `reset;` replaces the whole item with its [`Default`] value. The item has to be a mutable
+/// reference.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Default)]
+/// struct Counter { count: u32, history: Vec<u32> }
+///
+/// let mut counter = Counter { count: 3, history: vec![1, 2] };
+/// multicall! {
+///     &mut counter:
+///     reset;
+///     exec assert!(#.history.is_empty());
+///     count += 5;
+/// }
+/// assert_eq!((counter.count, counter.history.len()), (5, 0));
+/// ```
+///
+/// ## `finally`
+///
+/// `finally { ... };` holds statements that run on the item when the block is left, even if
+/// that happens early through `?` or `return`. The item has to be a mutable reference.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Job { steps: Vec<&'static str>, running: bool }
+/// impl Job {
+///     fn step(&mut self, name: &'static str) -> Result<(), &'static str> {
+///         if name.is_empty() { return Err("unnamed step"); }
+///         self.steps.push(name);
+///         Ok(())
+///     }
+/// }
+///
+/// fn run(job: &mut Job, second: &'static str) -> Result<(), &'static str> {
+///     multicall! {
+///         &mut *job:
+///         running = true;
+///         step("first")?;
+///         step(second)?;
+///         step("third")?;
+///         finally {
+///             running = false;
+///             steps.push("cleanup");
+///         };
+///     }
+///     Ok(())
+/// }
+///
+/// let mut job = Job { steps: Vec::new(), running: false };
+/// assert_eq!(run(&mut job, "second"), Ok(()));
+/// assert_eq!(job.steps, ["first", "second", "third", "cleanup"]);
+/// job.steps.clear();
+/// assert_eq!(run(&mut job, ""), Err("unnamed step"));
+/// assert_eq!((job.steps.as_slice(), job.running), (["first", "cleanup"].as_slice(), false));
+/// ```
+///
+/// ## `defer_in_block`
+///
+/// `defer_in_block operation;` is a single statement version of `finally`, which makes it handy
+/// in sub-blocks: the operation runs on the sub-block's item when the sub-block ends, before the
+/// next statement of the outer block. Several of them run in reverse order, after the `finally`
+/// block if there is one. Like `finally`, they run no matter where in the block they are written.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Buffer { data: Vec<u32>, flushed: bool }
+/// impl Buffer {
+///     fn flush(&mut self) { self.flushed = true; }
+/// }
+/// struct Writer { buffer: Buffer, log: Vec<bool> }
+///
+/// let mut writer = Writer { buffer: Buffer { data: Vec::new(), flushed: false }, log: Vec::new() };
+/// multicall! {
+///     &mut writer:
+///     {
+///         buffer:
+///         defer_in_block flush();
+///         defer_in_block data.push(0);
+///         data.push(1);
+///     };
+///     exec #.log.push(#.buffer.flushed);
+/// }
+/// assert_eq!(writer.buffer.data, [1, 0]);
+/// assert_eq!(writer.log, [true]);
+/// ```
+///
+/// ## `try_op`
+///
+/// `try_op operation or default` runs the operation and evaluates to `default` if it panics,
+/// using [`catch_unwind`](std::panic::catch_unwind). Requires the `std` feature.
+///
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use multicall::multicall;
+/// struct Parser { parsed: u32 }
+/// impl Parser {
+///     fn parse(&mut self, input: &str) -> u32 {
+///         self.parsed += 1;
+///         input.parse().unwrap()
+///     }
+/// }
+///
+/// let mut parser = Parser { parsed: 0 };
+/// let good = multicall! { &mut parser: try_op parse("5") or 0 };
+/// let bad = multicall! { &mut parser: try_op parse("five") or #.parsed * 100 };
+/// assert_eq!((good, bad), (5, 200));
+/// # }
+/// ```
+///
+/// ## Macro calls
+///
+/// A statement that is a macro call, like `name!(...)` or `name! { ... }`, isn't prefixed with
+/// the item. It is passed on as written, with `#` replaced by the item. Statements that are
+/// passed in as fragments by a `macro_rules!` macro work like written-out ones.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Counter { count: u32 }
+/// macro_rules! bump_twice {
+///     ($counter:expr) => { $counter.count += 1; $counter.count += 1; };
+/// }
+/// macro_rules! configure {
+///     ($target:expr; $($s:stmt;)*) => { multicall! { $target: $($s;)* } };
+/// }
+///
+/// let mut counter = Counter { count: 0 };
+/// multicall! {
+///     &mut counter:
+///     bump_twice!(#);
+///     bump_twice! { # };
+/// }
+/// configure!(&mut counter; count *= 10;);
+/// assert_eq!(counter.count, 40);
+/// ```
+///
+/// ## Sub-blocks
+///
+/// A `{ subexpr: ... };` statement runs its statements on `subexpr` of the current item. The
+/// header may be fallible: `{ get()?: ... };` binds the unwrapped value and propagates the error
+/// out of the surrounding function.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Settings { retries: u32 }
+/// struct App { settings: Option<Settings> }
+/// impl App {
+///     fn settings(&mut self) -> Result<&mut Settings, &'static str> {
+///         self.settings.as_mut().ok_or("no settings")
+///     }
+/// }
+///
+/// fn configure(app: &mut App) -> Result<(), &'static str> {
+///     multicall! {
+///         &mut *app:
+///         {
+///             settings()?:
+///             retries = 3;
+///         };
+///     }
+///     Ok(())
+/// }
+///
+/// let mut app = App { settings: Some(Settings { retries: 0 }) };
+/// assert_eq!(configure(&mut app), Ok(()));
+/// assert_eq!(app.settings.unwrap().retries, 3);
+/// assert_eq!(configure(&mut App { settings: None }), Err("no settings"));
+/// ```
+///
+/// Sub-blocks borrow their item mutably if the block's item is a `&mut` borrow, whatever it
+/// borrows, and immutably otherwise.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Counter { hits: Vec<u32> }
+/// struct Service { inner: Counter }
+/// impl Service {
+///     fn record(&mut self) {
+///         multicall! {
+///             &mut self.inner:
+///             { hits: exec Vec::push(#, 1); };
+///         }
+///     }
+/// }
+///
+/// let mut boxed = Box::new(Service { inner: Counter { hits: Vec::new() } });
+/// boxed.record();
+/// multicall! {
+///     &mut *boxed:
+///     { inner.hits: exec Vec::push(#, 2); };
+/// }
+/// assert_eq!(boxed.inner.hits, [1, 2]);
+/// let total;
+/// multicall! {
+///     boxed.inner:
+///     { hits: set total = #.iter().sum::<u32>(); };
+/// }
+/// assert_eq!(total, 3);
+/// ```
+///
+/// A header counts as a mutable borrow if it starts with `&mut`, also inside parentheses. Any other
+/// header, like a call or an owned value, gives sub-blocks an immutable borrow:
+///
+/// ```
+/// use multicall::multicall;
+/// let mut list = vec![vec![1], vec![2]];
+/// multicall! {
+///     (&mut list):
+///     { [0]: exec Vec::push(#, 3); };
+/// }
+/// assert_eq!(list, [vec![1, 3], vec![2]]);
+/// ```
+///
+/// ```compile_fail
+/// use multicall::multicall;
+/// let list = vec![vec![1], vec![2]];
+/// multicall! {
+///     list.clone():
+///     { [0]: exec Vec::push(#, 3); };
+/// }
+/// ```
+///
+/// Like any other statement, a sub-block has to be followed by a `;`, unless it is the last
+/// statement of its block.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Default)]
+/// struct Size { width: u32, height: u32 }
+/// #[derive(Default)]
+/// struct Window { size: Size, min: Size }
+///
+/// let mut window = Window::default();
+/// multicall! {
+///     &mut window:
+///     { size: width = 800; height = 600 };
+///     { min: width = 200 }
+/// }
+/// assert_eq!((window.size.width, window.size.height, window.min.width), (800, 600, 200));
+/// ```
+///
+/// ```compile_fail
+/// use multicall::multicall;
+/// #[derive(Default)]
+/// struct Size { width: u32, height: u32 }
+/// #[derive(Default)]
+/// struct Window { size: Size, min: Size }
+///
+/// let mut window = Window::default();
+/// multicall! {
+///     &mut window:
+///     { size: width = 800 }
+///     { min: width = 200 }
+/// }
+/// ```
+///
+/// A sub-block on a field or an element, like `{ items: ... };` or `{ rows[0]: ... };`, doesn't
+/// borrow it for the whole block. It is used in place by every statement, so the compiler picks
+/// `&` or `&mut` per method call: one that only reads it takes a shared borrow, which may overlap
+/// with other shared borrows of it. Sub-blocks that use `#` on its own, or that contain `reset`,
+/// `rebuild`, `yield`, `finally`, `defer_in_block`, `if_debug` or `with` still borrow it, as a
+/// `&mut` if the block's item is mutable. So do sub-blocks indexing with anything but a literal or a
+/// variable, so the index is only evaluated once.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Inventory { items: Vec<&'static str>, checked: bool }
+///
+/// let mut inventory = Inventory { items: vec!["rope", "lamp"], checked: false };
+/// let (first, count);
+/// multicall! {
+///     &mut inventory:
+///     {
+///         items:
+///         set first = first();
+///     };
+///     {
+///         items:
+///         set count = len();
+///     };
+///     exec assert_eq!((first, count), (Some(&"rope"), 2));
+///     checked = true;
+/// }
+/// assert!(inventory.checked);
+/// ```
+///
+/// ```
+/// use multicall::multicall;
+/// struct Board { rows: Vec<Vec<u8>> }
+///
+/// let mut board = Board { rows: vec![vec![1], vec![2, 3]] };
+/// let (first, second);
+/// multicall! {
+///     &mut board:
+///     {
+///         rows[0]:
+///         push(4);
+///         set first = last();
+///     };
+///     {
+///         rows[1]:
+///         set second = first();
+///         exec assert_eq!(#.len(), 2);
+///     };
+///     exec assert_eq!((first, second), (Some(&4), Some(&2)));
+///     {
+///         rows[1]:
+///         clear();
+///     };
+/// }
+/// assert_eq!(board.rows, [vec![1, 4], vec![]]);
+///
+/// let mut rows = vec![Vec::new(), Vec::new(), Vec::new()];
+/// let mut next = 0;
+/// let mut next = || { next += 1; next - 1 };
+/// multicall! {
+///     &mut rows:
+///     {
+///         [next()]:
+///         push(1);
+///         push(2);
+///     };
+/// }
+/// assert_eq!(rows, [vec![1, 2], vec![], vec![]]);
+/// ```
+///
+/// The header `#0` refers to the wrapped value of a newtype, like `0` would, but reads as the
+/// item's own value rather than a field.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Meters(f64);
+/// struct Route { length: Meters }
+///
+/// let mut route = Route { length: Meters(1.5) };
+/// multicall! {
+///     &mut route:
+///     {
+///         length:
+///         {
+///             #0:
+///             exec *# *= 2.0;
+///         };
+///     };
+/// }
+/// assert_eq!(route.length.0, 3.0);
+///
+/// struct Wrapper { inner: Meters }
+/// let mut wrapper = Wrapper { inner: Meters(2.0) };
+/// multicall! {
+///     &mut wrapper:
+///     {
+///         inner:
+///         exec #.0 += 1.0;
+///     };
+/// }
+/// assert_eq!(wrapper.inner.0, 3.0);
+/// ```
+///
+/// A header ending in an index, like `[0]` or `list[2]`, works on that element. If the index is a
+/// range, like `[..]` or `list[1..3]`, the statements run once for every element in it.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Samples { values: Vec<i32> }
+///
+/// let mut samples = Samples { values: vec![-5, 20, -3, 8] };
+/// multicall! {
+///     &mut samples:
+///     {
+///         values[1..3]:
+///         exec *# = (*#).clamp(0, 10);
+///     };
+///     {
+///         values[0]:
+///         exec *# = 0;
+///     };
+/// }
+/// assert_eq!(samples.values, [0, 10, 0, 8]);
+/// ```
+///
+/// For anything a plain path can't reach, the header `with |param| accessor` binds `param` to
+/// the current item and works on whatever `accessor` evaluates to, like the body of a closure.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Node { children: Vec<Node>, value: u32 }
+/// impl Node {
+///     fn last_mut(&mut self) -> Option<&mut Node> { self.children.last_mut() }
+/// }
+///
+/// let leaf = || Node { children: Vec::new(), value: 0 };
+/// let mut tree = Node { children: vec![leaf(), leaf()], value: 0 };
+/// multicall! {
+///     &mut tree:
+///     value = 1;
+///     {
+///         with |node| node.last_mut().unwrap():
+///         value = 2;
+///         children.push(leaf());
+///     };
+/// }
+/// assert_eq!((tree.value, tree.children[1].value), (1, 2));
+/// assert_eq!(tree.children[1].children.len(), 1);
+/// ```
+///
+/// ## Block value
+///
+/// Like any block, `multicall!` evaluates to its last statement if that isn't followed by a `;`.
+/// Ending with a bare `#` hands back the item itself. For a reference item this is the very
+/// reference that was passed in, so it keeps its lifetime and can be used after the block.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Config { port: u16, verbose: bool }
+///
+/// fn defaults(config: &mut Config) -> &mut Config {
+///     multicall! {
+///         config:
+///         port = 80;
+///         verbose = false;
+///         ##
+///     }
+/// }
+///
+/// let mut config = Config { port: 0, verbose: true };
+/// defaults(&mut config).port += 1;
+/// let config_ref = multicall! { &mut config: verbose = true; # };
+/// config_ref.port += 1;
+/// assert_eq!((config.port, config.verbose), (82, true));
+/// ```
+///
+/// `yield;` does the same as a bare `#`, but may be followed by a `;`, so it can end a block like
+/// any other statement. In a sub-block, it hands back the item of the sub-block.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Config { name: String, retries: u32 }
+///
+/// fn defaults(config: &mut Config) -> &mut Config {
+///     multicall! {
+///         config:
+///         retries = 3;
+///         yield;
+///     }
+/// }
+///
+/// let mut config = Config { name: "server".into(), retries: 0 };
+/// defaults(&mut config).retries += 1;
+/// let name = multicall! {
+///     &mut config:
+///     {
+///         name:
+///         push_str("-1");
+///         yield;
+///     }
+/// };
+/// name.push('!');
+/// assert_eq!((config.name.as_str(), config.retries), ("server-1!", 4));
+/// ```
+///
+/// The value is passed on as it is, so ignoring a `#[must_use]` value like a `Result` is still
+/// reported by the `unused_must_use` lint.
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// use multicall::multicall;
+/// struct Store { items: Vec<u32> }
+/// impl Store {
+///     fn add(&mut self, item: u32) -> Result<(), String> {
+///         self.items.push(item);
+///         Ok(())
+///     }
+/// }
+///
+/// fn main() {
+///     let mut store = Store { items: Vec::new() };
+///     multicall! {
+///         &mut store:
+///         add(1)
+///     };
+/// }
+/// ```
+///
+/// # Directives
+///
+/// Directives are written before the item expression and end with a `:` like it. They are only
+/// allowed at the top level and apply to all sub-blocks.
+///
+/// ## `wrap`
+///
+/// `wrap some_macro!:` passes every operation on the item to `some_macro!(...)`, which is handy
+/// for logging or timing. Only statements that produce a value (like method calls) are wrapped;
+/// assignments, `set`, `exec` and the sub-blocks themselves are left as they are.
+///
+/// ```
+/// use multicall::multicall;
+/// let mut log = Vec::new();
+/// macro_rules! record {
+///     ($e:expr) => {{
+///         log.push(stringify!($e).replace("__multicall_item__.", ""));
+///         $e
+///     }};
+/// }
+/// let mut items = Vec::new();
+/// multicall! {
+///     wrap record!:
+///     &mut items:
+///     push(1);
+///     push(2);
+///     exec assert_eq!(#.len(), 2);
+/// }
+/// assert_eq!(log, ["push(1)", "push(2)"]);
+/// ```
+///
+/// ## `postprocess`
+///
+/// `postprocess some_macro!:` passes all statements of the block, after they were turned into
+/// code, to `some_macro! { ... }` at once. Unlike `wrap`, the macro sees assignments and `exec`s
+/// too, and may change or reorder them. Since the item's variable is hygienic, the macro can only
+/// reach the item through the statements it is given. Earlier versions let it write
+/// `__multicall_item__` for that, which no longer resolves.
+///
+/// ```
+/// use multicall::multicall;
+/// let mut count = 0;
+/// macro_rules! counted {
+///     ($($statement:expr;)*) => {
+///         $(
+///             count += 1;
+///             $statement;
+///         )*
+///     };
+/// }
+/// let mut items = Vec::new();
+/// let length;
+/// multicall! {
+///     postprocess counted!:
+///     &mut items:
+///     push(1);
+///     exec #.push(2);
+///     set length = len();
+/// }
+/// assert_eq!((items.as_slice(), length), ([1, 2].as_slice(), 2));
+/// assert_eq!(count, 3);
+/// ```
+///
+/// ## `dispatch`
+///
+/// `dispatch cmd:` turns the block into a `match cmd { ... }`. Every statement is written as
+/// `Pattern => operation;` and becomes one arm running the operation on the item.
+///
+/// ```
+/// use multicall::multicall;
+/// enum Cmd { Reset, Add(u32) }
+/// fn run(counter: &mut Vec<u32>, cmd: Cmd) {
+///     multicall! {
+///         dispatch cmd:
+///         counter:
+///         Cmd::Reset => clear();
+///         Cmd::Add(n) => push(n);
+///     }
+/// }
+/// let mut counter = vec![1];
+/// run(&mut counter, Cmd::Reset);
+/// run(&mut counter, Cmd::Add(5));
+/// assert_eq!(counter, [5]);
+/// ```
+///
+/// `dispatch` is only taken for the directive if an expression follows it, so a variable of that
+/// name can still be the item.
+///
+/// ```
+/// use multicall::multicall;
+/// let dispatch = vec![3, 1, 2];
+/// let largest = multicall! { dispatch.iter(): max() };
+/// assert_eq!(largest, Some(&3));
+/// ```
+///
+/// ## `transitions`
+///
+/// `transitions event:` works like `dispatch`, but is meant for state machines where most events
+/// only apply in some states: events that don't match any of the arms are ignored.
+///
+/// ```
+/// use multicall::multicall;
+/// #[derive(Debug, PartialEq)]
+/// enum State { Idle, Running, Done }
+/// enum Event { Start, Tick, Stop, Reset }
+/// struct Machine { state: State, ticks: u32 }
+///
+/// fn handle(machine: &mut Machine, event: Event) {
+///     let running = machine.state == State::Running;
+///     multicall! {
+///         transitions (running, event):
+///         &mut *machine:
+///         (false, Event::Start) => state = State::Running;
+///         (true, Event::Tick) => ticks += 1;
+///         (true, Event::Stop) => state = State::Done;
+///     }
+/// }
+///
+/// let mut machine = Machine { state: State::Idle, ticks: 0 };
+/// for event in [Event::Tick, Event::Start, Event::Tick, Event::Tick, Event::Reset, Event::Stop] {
+///     handle(&mut machine, event);
+/// }
+/// assert_eq!((machine.state, machine.ticks), (State::Done, 2));
+///
+/// let transitions = vec!["start", "stop"];
+/// let length = multicall! { transitions[0]: len() };
+/// assert_eq!(length, 5);
+/// ```
+///
+/// ## `timed`
+///
+/// `timed into duration:` measures how long the block takes, including the item expression, and
+/// assigns the resulting [`Duration`](std::time::Duration) to the outer variable `duration`.
+/// Requires the `std` feature.
+///
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use multicall::multicall;
+/// use std::time::Duration;
+/// let duration;
+/// multicall! {
+///     timed into duration:
+///     &mut Vec::<u32>::new():
+///     push(1);
+///     exec std::thread::sleep(Duration::from_millis(1));
+/// }
+/// assert!(duration >= Duration::from_millis(1));
+/// # }
+/// ```
+///
+/// ## `guard_drop`
+///
+/// `guard_drop { ... }:` turns the block into a scope guard: the item is moved into a guard, which
+/// the block evaluates to, and the statements in the braces run on the item when the guard is
+/// dropped. The guard dereferences to the item, so it can still be used in the meantime.
+///
+/// ```
+/// use multicall::multicall;
+/// use std::cell::Cell;
+/// struct Transaction<'a> { writes: Vec<u32>, committed: &'a Cell<bool> }
+/// impl Transaction<'_> {
+///     fn commit(&mut self) { self.committed.set(!self.writes.is_empty()); }
+/// }
+///
+/// let committed = Cell::new(false);
+/// {
+///     let mut transaction = multicall! {
+///         guard_drop { commit(); }:
+///         Transaction { writes: Vec::new(), committed: &committed }:
+///         writes.push(1);
+///     };
+///     transaction.writes.push(2);
+///     assert!(!committed.get());
+/// }
+/// assert!(committed.get());
+/// ```
+///
+/// ## `track_changes`
+///
+/// `track_changes into changed:` pushes the name of every field the block assigns to, like `a = x;`
+/// or `a.b += x;`, to the outer collection `changed`, as long as the assignment changed its value.
+/// The assigned fields have to implement [`Clone`] and [`PartialEq`]. Statements starting with a
+/// keyword, like `let`, `exec` or `set_some`, aren't tracked, even if they assign something.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Point { x: i32, y: i32 }
+/// struct Widget { position: Point, label: String, visible: bool }
+///
+/// let mut widget = Widget { position: Point { x: 0, y: 0 }, label: "ok".into(), visible: true };
+/// let mut changed = Vec::new();
+/// multicall! {
+///     track_changes into changed:
+///     &mut widget:
+///     position.x += 5;
+///     position.y = 0;
+///     label = "ok".into();
+///     visible = false;
+/// }
+/// assert_eq!(changed, ["position.x", "visible"]);
+///
+/// let mut changed = Vec::new();
+/// let (mut count, mut last) = (0, 0);
+/// let size;
+/// multicall! {
+///     track_changes into changed:
+///     &mut widget:
+///     let step = 2;
+///     position.y += step;
+///     exec count = 1;
+///     set_some last = position.y.checked_mul(3);
+///     match_set size = { 0 => "empty", _ => "some" } on position.y;
+///     raw count += 1;
+/// }
+/// assert_eq!(changed, ["position.y"]);
+/// assert_eq!((count, last, size), (2, 6, "some"));
+/// ```
+///
+/// ## `via trait`
+///
+/// `via trait Trait:` calls methods through the given trait, as `Trait::method(item, args)`, even
+/// when the item has an inherent method of the same name. The item should be a reference.
+///
+/// ```
+/// use multicall::multicall;
+/// trait Named {
+///     fn name(&self) -> &'static str;
+///     fn rename(&mut self, name: &'static str);
+/// }
+/// struct Item { name: &'static str }
+/// impl Item {
+///     fn name(&self) -> &'static str { "inherent" }
+///     fn rename(&mut self, _: &'static str) {}
+/// }
+/// impl Named for Item {
+///     fn name(&self) -> &'static str { self.name }
+///     fn rename(&mut self, name: &'static str) { self.name = name; }
+/// }
+///
+/// let mut item = Item { name: "trait" };
+/// let name = multicall! {
+///     via trait Named:
+///     &mut item:
+///     rename("renamed");
+///     name()
+/// };
+/// assert_eq!((name, item.name()), ("renamed", "inherent"));
+/// ```
+///
+/// ## `collect_errors`
+///
+/// `collect_errors into errors:` keeps going when a statement ending in `?` fails and pushes its
+/// error into the outer collection `errors` instead of returning it.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Form { name: String, age: u32 }
+/// impl Form {
+///     fn check_name(&self) -> Result<(), String> {
+///         if self.name.is_empty() { Err("name is empty".into()) } else { Ok(()) }
+///     }
+///     fn check_age(&self, max: u32) -> Result<(), String> {
+///         if self.age > max { Err(format!("age is over {max}")) } else { Ok(()) }
+///     }
+/// }
+///
+/// let form = Form { name: String::new(), age: 200 };
+/// let mut errors = Vec::new();
+/// multicall! {
+///     collect_errors into errors:
+///     &form:
+///     check_name()?;
+///     check_age(150)?;
+///     check_age(250)?;
+/// }
+/// assert_eq!(errors, ["name is empty", "age is over 150"]);
+///
+/// let errors = {
+///     #[allow(dead_code)]
+///     enum Outcome { Ok, Err }
+///     use Outcome::*;
+///     let mut errors = Vec::new();
+///     multicall! {
+///         collect_errors into errors:
+///         &form:
+///         check_name()?;
+///     }
+///     errors
+/// };
+/// assert_eq!(errors, ["name is empty"]);
+/// ```
+///
+/// ## `expand`
+///
+/// `expand:` shows the code the block expands to in a `deprecated` warning, without changing
+/// what the block does. It is meant for debugging and should be removed afterwards, as it fails
+/// builds that deny warnings. In a sub-block, it only shows what the sub-block expands to, and
+/// is only taken for the directive if the sub-block's header follows it, since `expand` could
+/// also be a field.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Config { expand: bool, tags: Vec<u32> }
+///
+/// let mut list = Vec::new();
+/// multicall! {
+///     expand:
+///     &mut list:
+///     push(1);
+///     exec #.push(2);
+/// }
+/// assert_eq!(list, [1, 2]);
+///
+/// let mut config = Config { expand: false, tags: Vec::new() };
+/// multicall! {
+///     &mut config:
+///     {
+///         expand:
+///         tags:
+///         push(3);
+///     };
+///     { expand: exec *# = true; };
+/// }
+/// assert_eq!((config.expand, config.tags), (true, vec![3]));
+/// ```
+///
+/// ```compile_fail
+/// #![deny(deprecated)]
+/// use multicall::multicall;
+/// let mut list = Vec::new();
+/// multicall! {
+///     expand:
+///     &mut list:
+///     push(1);
+/// }
+/// ```
+///
+/// # Items
+///
+/// Besides a plain expression, the item can be given in one of the following forms.
+///
+/// ## `lock_async`
+///
+/// `lock_async mutex:` awaits `mutex.lock()` and works on the locked value, holding the guard
+/// until the end of the block. This has to be used in an async context.
+///
+/// ```
+/// use multicall::multicall;
+/// use std::sync::{Mutex, MutexGuard};
+/// # use std::{future::Future, pin::pin, task::{Context, Poll, Waker}};
+/// struct AsyncMutex<T>(Mutex<T>);
+/// impl<T> AsyncMutex<T> {
+///     async fn lock(&self) -> MutexGuard<'_, T> {
+///         self.0.lock().unwrap()
+///     }
+/// }
+///
+/// async fn fill(list: &AsyncMutex<Vec<u32>>) {
+///     multicall! {
+///         lock_async list:
+///         push(1);
+///         push(2);
+///     }
+/// }
+///
+/// let list = AsyncMutex(Mutex::new(Vec::new()));
+/// # let mut future = pin!(fill(&list));
+/// # while future.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending() {}
+/// assert_eq!(*list.0.lock().unwrap(), [1, 2]);
+///
+/// // not followed by an expression, so this is a plain variable
+/// let lock_async = vec![1, 2, 3];
+/// let total = multicall! { lock_async.iter(): sum::<u32>() };
+/// assert_eq!(total, 6);
+/// ```
+///
+/// ## `nonempty`
+///
+/// `nonempty collection:` skips all statements if `collection.is_empty()`.
+///
+/// ```
+/// use multicall::multicall;
+/// let mut smallest = Vec::new();
+/// for mut list in [vec![3, 1, 2], vec![]] {
+///     multicall! {
+///         nonempty &mut list:
+///         sort();
+///         exec smallest.push(#[0]);
+///     }
+/// }
+/// assert_eq!(smallest, [1]);
+///
+/// let nonempty = vec![4, 5];
+/// let squared = multicall! { nonempty.len(): pow(2) };
+/// assert_eq!(squared, 4);
+/// ```
+///
+/// ## `validate`
+///
+/// `validate item:` runs all `check condition: message;` statements of the block, recording the
+/// message of every check whose condition doesn't hold. The block then evaluates to `Ok(item)` if
+/// all checks passed, or to `Err` with the messages in order. Like the value of a `set`, the
+/// condition is prefixed with the item unless it starts with `#`. This requires the `std` feature.
+///
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use multicall::multicall;
+/// #[derive(Debug)]
+/// struct User { name: String, age: u32, email: String }
+///
+/// fn validated(user: &User) -> Result<&User, Vec<String>> {
+///     multicall! {
+///         validate user:
+///         check name.len() > 0: "name is empty";
+///         check age <= 150: format!("age {} is too high", #.age);
+///         check email.contains('@'): "email has no @";
+///     }
+/// }
+///
+/// let user = User { name: "Ada".into(), age: 36, email: "ada@example.com".into() };
+/// assert!(validated(&user).is_ok_and(|x| std::ptr::eq(x, &user)));
+/// let user = User { name: String::new(), age: 200, email: "ada@example.com".into() };
+/// assert_eq!(validated(&user).unwrap_err(), ["name is empty", "age 200 is too high"]);
+/// # }
+/// ```
+///
+/// Without an expression after it, `validate` is just a name, and doesn't need the `std` feature.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Form { rules: Vec<&'static str> }
+///
+/// let validate = Form { rules: vec!["required", "email"] };
+/// let count = multicall! { validate.rules.iter(): count() };
+/// assert_eq!(count, 2);
+/// ```
+///
+/// ## `try`
+///
+/// `try constructor?:` works on the value the fallible constructor returns, propagating its error
+/// out of the surrounding function. The `?` may be left out.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Port { number: u16, open: bool }
+/// impl Port {
+///     fn new(number: u16) -> Result<Port, String> {
+///         match number {
+///             0 => Err("port 0 is reserved".to_string()),
+///             number => Ok(Port { number, open: false }),
+///         }
+///     }
+///     fn open(&mut self) { self.open = true; }
+/// }
+///
+/// fn open(number: u16) -> Result<(u16, bool), String> {
+///     multicall! {
+///         try &mut Port::new(number)?:
+///         open();
+///         exec Ok((#.number, #.open))
+///     }
+/// }
+///
+/// assert_eq!(open(8080), Ok((8080, true)));
+/// assert_eq!(open(0), Err("port 0 is reserved".to_string()));
+/// ```
+///
+/// ## Several targets
+///
+/// `(a, b, c):` runs the whole block on `a`, then on `b` and then on `c`, including its `set` and
+/// `exec` statements. A parenthesized item without a comma is still a single item.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Light { on: bool, level: u8 }
+///
+/// let light = |level| Light { on: false, level };
+/// let (mut hall, mut kitchen, mut porch) = (light(0), light(3), light(0));
+/// let mut order = Vec::new();
+/// multicall! {
+///     (&mut hall, &mut kitchen, &mut porch):
+///     on = true;
+///     level += 5;
+///     exec order.push(#.level * 2);
+/// }
+/// multicall! {
+///     (&mut porch):
+///     level = 1;
+/// }
+/// assert!(hall.on && kitchen.on && porch.on);
+/// assert_eq!((hall.level, kitchen.level, porch.level), (5, 8, 1));
+/// assert_eq!(order, [10, 16, 10]);
+/// ```
+///
+/// ## Attributes
+///
+/// Attributes in front of the item, like `#[allow(unused_parens)] item:`, are put on the `let`
+/// that binds it.
+///
+/// ```
+/// #![deny(unused_parens)]
+/// use multicall::multicall;
+///
+/// fn main() {
+///     let mut list = Vec::new();
+///     multicall! {
+///         #[allow(unused_parens)]
+///         (&mut list):
+///         push(1);
+///     }
+///     assert_eq!(list, [1]);
+/// }
+/// ```
+///
+/// ## `zip`
+///
+/// `zip first, second:` runs the block for each pair of elements of two collections, in
+/// lockstep. The statements work on the element of `first`, written `#1` or `#`, and refer to the
+/// element of `second` as `#2`.
+///
+/// ```
+/// use multicall::multicall;
+/// use std::ops::AddAssign;
+/// let mut totals = vec![1, 2, 3];
+/// let extra = [10, 20, 30, 40];
+/// let mut pairs = Vec::new();
+/// multicall! {
+///     zip &mut totals, &extra:
+///     add_assign(*#2);
+///     exec pairs.push((*#1, *#2));
+/// }
+/// assert_eq!(totals, [11, 22, 33]);
+/// assert_eq!(pairs, [(11, 10), (22, 20), (33, 30)]);
+/// ```
+///
+/// ## Patterns
+///
+/// `Pattern = item:` destructures the item. Statements are then written against the bindings
+/// and not prefixed with the item, which allows borrowing several fields at once. Sub-blocks
+/// work on a binding.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Point { xs: Vec<u32>, y: u32 }
+///
+/// let mut point = Point { xs: Vec::new(), y: 1 };
+/// multicall! {
+///     Point { xs, y } = &mut point:
+///     xs.push(*y);
+///     *y += 1;
+///     {
+///         xs:
+///         push(3);
+///         insert(0, 0);
+///     };
+/// }
+/// assert_eq!((point.xs, point.y), (vec![0, 1, 3], 2));
+/// ```
+///
+/// ## Names
+///
+/// `item as name:` binds the item to `name`, which works like `#` but stays usable in sub-blocks,
+/// where `#` refers to the item of the sub-block. Sub-block headers may be named the same way.
+///
+/// ```
+/// use multicall::multicall;
+/// struct Line { price: u32, total: u32 }
+/// struct Order { lines: Vec<Line>, discount: u32, count: usize }
+///
+/// let mut order = Order { lines: vec![Line { price: 10, total: 0 }], discount: 3, count: 0 };
+/// multicall! {
+///     &mut order as order:
+///     {
+///         lines[0] as line:
+///         total = line.price - order.discount;
+///     };
+///     set order.count = #.lines.len();
+/// }
+/// assert_eq!((order.lines[0].total, order.count), (7, 1));
+/// ```
+///
+/// A name has to start with a lowercase letter and can't be a primitive type, so a header ending
+/// in a cast like `as u32` or `as Meters` still converts the item.
+///
+/// ```
+/// use multicall::multicall;
+/// let n: u8 = 44;
+/// let total;
+/// multicall! {
+///     n as u32:
+///     exec total = # + 256;
+/// }
+/// assert_eq!(total, 300);
+/// ```
+///
+/// ## Types
+///
+/// `expr: Type =>` gives the item a type, for expressions like `Default::default()` that can't
+/// be inferred otherwise.
+///
+/// ```
+/// use multicall::{multicall, with};
+/// #[derive(Default)]
+/// struct Options { depth: u32, verbose: bool }
+///
+/// let options = with! {
+///     Default::default(): Options =>
+///     depth = 3;
+///     verbose = true;
+/// };
+/// assert_eq!((options.depth, options.verbose), (3, true));
+/// let length = multicall! { Default::default(): Vec<u8> => len() };
+/// assert_eq!(length, 0);
+/// ```
+///
+#[proc_macro]
+pub fn multicall(input: TokenStream) -> TokenStream {
+    let input = escape(input);
+    let output = multicall_internal(input, false, &Context::default());
+    output.into_iter().map(unescape).collect()
+}
+
+/// Like [`multicall!`], but always evaluates to the item, so a value can be created and set up
+/// in a single expression. The statements are the same as in `multicall!`, so `set`, `exec` and
+/// the like still write to variables outside of the block.
+///
+/// ```
+/// use multicall::with;
+/// #[derive(Default)]
+/// struct Thing { a: u32, b: u32, tags: Vec<&'static str> }
+///
+/// let mut total = 0;
+/// let thing = with! {
+///     Thing::default():
+///     a = 1;
+///     b = 2;
+///     tags.push("new");
+///     set total += a + #.b;
+///     exec total *= 10;
+/// };
+/// assert_eq!((thing.a, thing.b, thing.tags.len(), total), (1, 2, 1, 30));
+///
+/// let mut list = vec![3, 1, 2];
+/// let sorted = with! { &mut list: sort() };
+/// sorted.push(4);
+/// assert_eq!(list, [1, 2, 3, 4]);
+/// ```
+#[proc_macro]
+pub fn with(input: TokenStream) -> TokenStream {
+    let input = escape(input);
+    let ctx = Context {
+        is_with: true,
+        ..Context::default()
+    };
+    let output = multicall_internal(input, false, &ctx);
+    output.into_iter().map(unescape).collect()
+}
+
+/// Puts the expanded block into a `deprecated` warning at the `expand` directive.
+fn expand(span: Span, output: TokenStream) -> Vec<TokenTree> {
+    let expanded: TokenStream = output.clone().into_iter().map(unescape).collect();
+    let mut note = code("note =");
+    note.push(TokenTree::Literal(Literal::string(&format!(
+        "multicall expands to: {expanded}"
+    ))));
+    let mut attribute = code("deprecated");
+    attribute.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(note),
+    )));
+    let mut words = code("#");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Bracket,
+        TokenStream::from_iter(attribute),
+    )));
+    words.extend(code("struct __MulticallExpanded__; let _ ="));
+    words.push(TokenTree::Ident(Ident::new("__MulticallExpanded__", span)));
+    words.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    words.extend(output);
+    vec![TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(words),
+    ))]
+}
+
+/// Replaces every `##` by a placeholder, so it isn't taken for the item, and marks the names of
+/// the macro's own variables where they are written out, like in the output of an outer
+/// `multicall!`. `unescape` turns the placeholders into a literal `#` once the block is generated.
+fn escape(input: TokenStream) -> TokenStream {
+    let mut tokens = Vec::new();
+    let mut iter = input.into_iter().peekable();
+    while let Some(x) = iter.next() {
+        match x {
+            TokenTree::Group(g) => {
+                let mut escaped = Group::new(g.delimiter(), escape(g.stream()));
+                escaped.set_span(g.span());
+                tokens.push(TokenTree::Group(escaped));
+            }
+            TokenTree::Punct(p)
+                if p.as_char() == '#'
+                    && p.spacing() == Spacing::Joint
+                    && iter.peek().is_some_and(|x| is_punct(x, '#')) =>
+            {
+                iter.next();
+                tokens.push(TokenTree::Ident(Ident::new("__multicall_hash__", p.span())));
+            }
+            TokenTree::Ident(x) if x.to_string().starts_with("__multicall_") => {
+                let name = format!("__written{x}");
+                tokens.push(TokenTree::Ident(Ident::new(&name, x.span())));
+            }
+            x => tokens.push(x),
+        }
+    }
+    TokenStream::from_iter(tokens)
+}
+
+/// Turns the placeholders of `escape` back into a `#` and the marked names back into what was
+/// written. The variables the block declares get mixed-site hygiene, so names written by the
+/// user, even in a `let` of their own, never refer to them.
+fn unescape(token: TokenTree) -> TokenTree {
+    match token {
+        TokenTree::Group(x) => {
+            let mut g = Group::new(
+                x.delimiter(),
+                x.stream().into_iter().map(unescape).collect(),
+            );
+            g.set_span(x.span());
+            TokenTree::Group(g)
+        }
+        TokenTree::Ident(x) if x.to_string() == "__multicall_hash__" => {
+            let mut hash = Punct::new('#', Spacing::Alone);
+            hash.set_span(x.span());
+            TokenTree::Punct(hash)
+        }
+        TokenTree::Ident(x) if x.to_string().starts_with("__written__multicall_") => {
+            TokenTree::Ident(Ident::new(&x.to_string()["__written".len()..], x.span()))
+        }
+        TokenTree::Ident(x) if x.to_string().starts_with("__multicall_") => TokenTree::Ident(
+            Ident::new(&x.to_string(), Span::mixed_site().located_at(x.span())),
+        ),
+        x => x,
+    }
+}
+
+/// Settings that apply to all statements of a block, including its sub-blocks.
+#[derive(Default, Clone)]
+struct Context {
+    is_mut: bool,
+    /// Macro (including the `!`) that every operation on the item gets wrapped in.
+    wrap: Option<Vec<TokenTree>>,
+    /// Trait path that method calls on the item are resolved through.
+    via: Option<Vec<TokenTree>>,
+    /// Collection that errors of fallible statements get pushed into.
+    errors: Option<Vec<TokenTree>>,
+    /// Whether the item was destructured by a pattern, so statements work on its bindings.
+    is_destructured: bool,
+    /// Whether the item is `validate`d, so `check` statements collect their failures.
+    is_validated: bool,
+    /// Whether the block is a `with!`, which always evaluates to its item.
+    is_with: bool,
+    /// How many sub-blocks deep the block is, which gives every block a label of its own.
+    depth: usize,
+}
+
+fn multicall_internal(input: TokenStream, is_recursed: bool, parent: &Context) -> TokenStream {
+    let mut ctx = parent.clone();
+    if is_recursed {
+        ctx.depth += 1;
+    }
+    let mut dispatch = None;
+    let mut timed = None;
+    let mut postprocess = None;
+    let mut changes = None;
+    let mut guard = None;
+    let mut iter = input.into_iter();
+    trace!("creating new multicall block...");
+    let Some(mut header) = read_header(&mut iter) else {
+        return TokenStream::from_iter(compile_error(
+            "multicall: missing ':' after the item expression",
+            Span::call_site(),
+        ));
+    };
+    // `expand:` in a sub-block could be a field as well, so it's only a directive if a header follows
+    if is_recursed
+        && matches!(header.as_slice(), [TokenTree::Ident(x)] if x.to_string() == "expand")
+    {
+        let rest: Vec<TokenTree> = iter.collect();
+        let end = rest
+            .iter()
+            .position(|x| is_punct(x, ';'))
+            .unwrap_or(rest.len());
+        if find_colon(&rest[..end]).is_some()
+            && !matches!(
+                keyword(&rest[..end]).as_deref(),
+                Some("let" | "return_if" | "exec" | "raw")
+            )
+        {
+            trace!("found 'expand' directive in a sub-block. expanding the rest of it.");
+            let output = multicall_internal(TokenStream::from_iter(rest), true, parent);
+            return TokenStream::from_iter(expand(header[0].span(), output));
+        }
+        iter = TokenStream::from_iter(rest).into_iter();
+    }
+    // the directives as written, to repeat them for every target of a block with several
+    let mut directives = Vec::new();
+    if !is_recursed {
+        trace!("checking for directives...");
+        loop {
+            let raw = header.clone();
+            if header.len() >= 3
+                && header[0].to_string() == "wrap"
+                && is_punct(&header[header.len() - 1], '!')
+            {
+                trace!("found 'wrap' directive.");
+                ctx.wrap = Some(header.split_off(1));
+            } else if header.len() >= 3
+                && header[0].to_string() == "postprocess"
+                && is_punct(&header[header.len() - 1], '!')
+            {
+                trace!("found 'postprocess' directive.");
+                postprocess = Some(header.split_off(1));
+            } else if matches!(header.as_slice(), [TokenTree::Ident(x), TokenTree::Group(g)]
+                if x.to_string() == "guard_drop" && g.delimiter() == Delimiter::Brace)
+            {
+                trace!("found 'guard_drop' directive.");
+                let Some(TokenTree::Group(cleanup)) = header.pop() else {
+                    unreachable!()
+                };
+                guard = Some(cleanup.stream());
+            } else if has_prefix(&header, "dispatch") {
+                trace!("found 'dispatch' directive.");
+                dispatch = Some((header.split_off(1), false));
+            } else if has_prefix(&header, "transitions") {
+                trace!("found 'transitions' directive.");
+                dispatch = Some((header.split_off(1), true));
+            } else if header.len() >= 3
+                && header[0].to_string() == "timed"
+                && header[1].to_string() == "into"
+            {
+                trace!("found 'timed' directive.");
+                if cfg!(not(feature = "std")) {
+                    return TokenStream::from_iter(compile_error(
+                        "multicall: `timed` requires the `std` feature",
+                        header[0].span(),
+                    ));
+                }
+                timed = Some(header.split_off(2));
+            } else if header.len() >= 3
+                && header[0].to_string() == "track_changes"
+                && header[1].to_string() == "into"
+            {
+                trace!("found 'track_changes' directive.");
+                changes = Some(header.split_off(2));
+            } else if header.len() >= 3
+                && header[0].to_string() == "via"
+                && header[1].to_string() == "trait"
+            {
+                trace!("found 'via trait' directive.");
+                ctx.via = Some(header.split_off(2));
+            } else if matches!(header.as_slice(), [TokenTree::Ident(x)] if x.to_string() == "expand")
+            {
+                trace!("found 'expand' directive. expanding the rest of the block.");
+                let mut input = directives;
+                input.extend(iter);
+                let output = multicall_internal(TokenStream::from_iter(input), false, parent);
+                return TokenStream::from_iter(expand(header[0].span(), output));
+            } else if header.len() >= 3
+                && header[0].to_string() == "collect_errors"
+                && header[1].to_string() == "into"
+            {
+                trace!("found 'collect_errors' directive.");
+                ctx.errors = Some(header.split_off(2));
+            } else {
+                break;
+            }
+            directives.extend(raw);
+            directives.push(TokenTree::Punct(Punct::new(':', Spacing::Alone)));
+            header = match read_header(&mut iter) {
+                Some(header) => header,
+                None => {
+                    return TokenStream::from_iter(compile_error(
+                        "multicall: missing ':' after the item expression",
+                        Span::call_site(),
+                    ))
+                }
+            };
+        }
+    }
+    if header.is_empty() {
+        return TokenStream::from_iter(compile_error(
+            "multicall: item expression is empty",
+            Span::call_site(),
+        ));
+    }
+    // `(a, b, c):` runs the block once for every target
+    if let [TokenTree::Group(g)] = header.as_slice() {
+        let targets: Vec<TokenTree> = g.stream().into_iter().collect();
+        if !is_recursed
+            && g.delimiter() == Delimiter::Parenthesis
+            && targets.iter().any(|x| is_punct(x, ','))
+        {
+            trace!("item is a list of targets. generating a block for each.");
+            let rest: Vec<TokenTree> = iter.collect();
+            let mut blocks = Vec::new();
+            for target in targets.split(|x| is_punct(x, ',')) {
+                if target.is_empty() {
+                    continue;
+                }
+                let mut input = directives.clone();
+                input.extend(target.iter().cloned());
+                input.push(TokenTree::Punct(Punct::new(':', Spacing::Alone)));
+                input.extend(rest.iter().cloned());
+                blocks.extend(multicall_internal(
+                    TokenStream::from_iter(input),
+                    false,
+                    parent,
+                ));
+                blocks.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+            }
+            return TokenStream::from(TokenTree::Group(Group::new(
+                Delimiter::Brace,
+                TokenStream::from_iter(blocks),
+            )));
+        }
+    }
+    // `expr: Type =>` gives the item's `let` a type
+    let mut annotation = None;
+    if !is_recursed && dispatch.is_none() {
+        let rest: Vec<TokenTree> = iter.collect();
+        let end = rest
+            .iter()
+            .position(|x| is_punct(x, ';'))
+            .unwrap_or(rest.len());
+        iter = match find_fat_arrow(&rest[..end]) {
+            Some(arrow) if arrow > 0 => {
+                trace!("item has a type annotation.");
+                annotation = Some(rest[..arrow].to_vec());
+                TokenStream::from_iter(rest.into_iter().skip(arrow + 2)).into_iter()
+            }
+            _ => TokenStream::from_iter(rest).into_iter(),
+        };
+    }
+    // attributes in front of the item are put on its `let`
+    let mut attributes = Vec::new();
+    while matches!(header.as_slice(), [x, TokenTree::Group(g), _, ..]
+        if is_punct(x, '#') && g.delimiter() == Delimiter::Bracket)
+    {
+        trace!("found attribute on the item.");
+        let rest = header.split_off(2);
+        attributes.extend(core::mem::replace(&mut header, rest));
+    }
+    let is_lock = !is_recursed && has_prefix(&header, "lock_async");
+    if is_lock {
+        trace!("item is 'lock_async'. holding the lock for the whole block.");
+        header.remove(0);
+        ctx.is_mut = true;
+    }
+    let is_nonempty = !is_recursed && has_prefix(&header, "nonempty");
+    if is_nonempty {
+        trace!("item is 'nonempty'. skipping the block if it is empty.");
+        header.remove(0);
+    }
+    if !is_recursed && has_prefix(&header, "validate") {
+        trace!("item is 'validate'. collecting the failures of its checks.");
+        if cfg!(not(feature = "std")) {
+            return TokenStream::from_iter(compile_error(
+                "multicall: `validate` requires the `std` feature",
+                header[0].span(),
+            ));
+        }
+        header.remove(0);
+        ctx.is_validated = true;
+    }
+    if !is_recursed && header.len() >= 2 && header[0].to_string() == "try" {
+        trace!("item is 'try'. propagating the error of its constructor.");
+        header.remove(0);
+        if !header.last().is_some_and(|x| is_punct(x, '?')) {
+            header.push(TokenTree::Punct(Punct::new('?', Spacing::Alone)));
+        }
+    }
+    let zipped = match header.iter().position(|x| is_punct(x, ',')) {
+        Some(comma) if !is_recursed && header[0].to_string() == "zip" => {
+            trace!("item is 'zip'. running the block for each pair of elements.");
+            let other = header.split_off(comma + 1);
+            header.truncate(comma);
+            header.remove(0);
+            Some(other)
+        }
+        _ => None,
+    };
+    // `Pattern = item:` destructures the item instead of binding it
+    let pattern = match find_assignment(&header) {
+        Some(eq)
+            if !is_recursed
+                && !matches!(&header[eq - 1], TokenTree::Punct(p) if p.spacing() == Spacing::Joint) =>
+        {
+            trace!("item is destructured by a pattern.");
+            let value = header.split_off(eq + 1);
+            header.pop();
+            ctx.is_destructured = true;
+            Some(core::mem::replace(&mut header, value))
+        }
+        _ => None,
+    };
+    // `item as name:` binds the item to `name`, which sub-blocks don't shadow. Names that could be
+    // a type, like `u32` or `Meters`, are left as casts.
+    let alias = match header.as_slice() {
+        [.., TokenTree::Ident(r#as), TokenTree::Ident(name)]
+            if header.len() >= 3
+                && pattern.is_none()
+                && r#as.to_string() == "as"
+                && is_binding_name(&name.to_string()) =>
+        {
+            trace!("item is named '{name}'.");
+            let name = name.clone();
+            header.truncate(header.len() - 2);
+            Some(name)
+        }
+        _ => None,
+    };
+    let mut dat = if is_recursed && parent.is_destructured {
+        trace!("parent block is destructured. borrowing from its bindings.");
+        ctx.is_destructured = false;
+        let mut v = vec![TokenTree::Punct(Punct::new('&', Spacing::Alone))];
+        if ctx.is_mut {
+            v.push(TokenTree::Ident(Ident::new("mut", Span::call_site())));
+        }
+        if matches!(header.as_slice(), [TokenTree::Ident(_)]) {
+            v.push(TokenTree::Punct(Punct::new('*', Spacing::Alone)));
+        }
+        v
+    } else if is_recursed {
+        trace!("inserting multicall item because this is a recursed block.");
+        let mut v = vec![
+            TokenTree::Punct(Punct::new('&', Spacing::Alone)),
+            TokenTree::Ident(Ident::new("mut", Span::call_site())),
+            TokenTree::Ident(Ident::new("__multicall_item__", Span::call_site())),
+            TokenTree::Punct(Punct::new('.', Spacing::Alone)),
+        ];
+        if !ctx.is_mut {
+            v.remove(1);
+        }
+        v
+    } else {
+        Vec::new()
+    };
+    trace!("initialized. reading item...");
+    if is_recursed
+        && matches!(header.as_slice(), [x, TokenTree::Literal(l)] if is_punct(x, '#') && l.to_string() == "0")
+    {
+        trace!("sub-block header is '#0'. using the wrapped value.");
+        header.remove(0);
+    }
+    // `with |param| accessor:` binds the parameter to the parent item and uses the accessor
+    let mut accessor = Vec::new();
+    if is_recursed
+        && !parent.is_destructured
+        && header.len() >= 4
+        && header[0].to_string() == "with"
+        && is_punct(&header[1], '|')
+    {
+        if let Some(end) = header.iter().skip(2).position(|x| is_punct(x, '|')) {
+            trace!("sub-block header is 'with'. using the accessor.");
+            let body = header.split_off(end + 3);
+            header.truncate(end + 2);
+            accessor.push(TokenTree::Ident(Ident::new("let", header[0].span())));
+            accessor.extend(header.drain(2..));
+            if ctx.is_mut {
+                accessor.extend(code("= &mut *__multicall_item__;"));
+            } else {
+                accessor.extend(code("= &*__multicall_item__;"));
+            }
+            dat = Vec::new();
+            header = body;
+        }
+    }
+    let is_range = is_recursed
+        && matches!(header.last(), Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Bracket && is_range(g));
+    if is_recursed
+        && !parent.is_destructured
+        && matches!(header.first(), Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Bracket)
+    {
+        trace!("sub-block header is an index. not inserting a '.'.");
+        dat.pop();
+    }
+    let mut statements = split_statements(iter);
+    // A sub-block on a plain field path, which may index into the fields, doesn't borrow the field
+    // up front. The path is inserted wherever the item is used instead, so the compiler picks `&`
+    // or `&mut` for every operation.
+    let is_place = |x: &TokenTree| match x {
+        TokenTree::Ident(_) | TokenTree::Literal(_) => true,
+        // other indices might have side effects, so they are only evaluated once
+        TokenTree::Group(g) => {
+            g.delimiter() == Delimiter::Bracket
+                && matches!(
+                    g.stream().into_iter().collect::<Vec<_>>().as_slice(),
+                    [TokenTree::Ident(_) | TokenTree::Literal(_)]
+                )
+        }
+        x => is_punct(x, '.'),
+    };
+    let is_projected = is_recursed
+        && !is_range
+        && alias.is_none()
+        && !parent.is_destructured
+        && accessor.is_empty()
+        && ctx.via.is_none()
+        && header.first().is_some_and(|x| !is_punct(x, '.'))
+        && header.iter().all(is_place)
+        && !statements.iter().any(|(stmt, _)| needs_borrow(stmt));
+    if is_projected {
+        trace!("sub-block header is a field path. projecting instead of borrowing.");
+        dat.retain(|x| !is_punct(x, '&') && x.to_string() != "mut");
+    }
+    if is_mut_borrow(&header) {
+        trace!("item is a mutable borrow.");
+        ctx.is_mut = true;
+    }
+    dat.extend(header);
+    // the item of a sub-block gets its own name, so it can be told apart from the parent's
+    let binder = match alias {
+        Some(ref name) => name.clone(),
+        None if is_recursed => Ident::new("__multicall_inner__", Span::call_site()),
+        None => Ident::new("__multicall_item__", Span::call_site()),
+    };
+    trace!("item read. writing initial let statement.");
+    let mut ts = TokenStream::from_iter(accessor);
+    if timed.is_some() {
+        ts.extend(code(
+            "let __multicall_start__ = ::std::time::Instant::now();",
+        ));
+    }
+    if is_lock {
+        ts.extend(code("let mut __multicall_guard__ ="));
+        ts.extend([TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from_iter(dat),
+        ))]);
+        ts.extend(code(".lock().await;"));
+        dat = code("&mut *__multicall_guard__");
+    }
+    if !is_range && !is_projected && zipped.is_none() {
+        // `with!` is mostly used to set up a new value, which has to be mutable for that
+        let is_owned_with = !is_recursed
+            && ctx.is_with
+            && pattern.is_none()
+            && !dat.first().is_some_and(|x| is_punct(x, '&'));
+        ts.extend(attributes);
+        if is_owned_with {
+            ts.extend(code("#[allow(unused_mut)]"));
+        }
+        ts.extend(vec![TokenTree::Ident(Ident::new("let", Span::call_site()))]);
+        if pattern.is_none()
+            && statements.iter().any(|(stmt, _)| {
+                has_keyword(stmt, "take")
+                    || has_keyword(stmt, "rebuild")
+                    || has_pipe(stmt)
+                    || has_item_mut(stmt)
+            })
+        {
+            trace!("found 'take' or 'rebuild'. binding the item mutably.");
+            ts.extend(code("mut"));
+        } else if is_owned_with {
+            trace!("block is 'with!' on an owned item. binding it mutably.");
+            ts.extend(code("mut"));
+        }
+        match pattern {
+            Some(pattern) => ts.extend(pattern),
+            None => ts.extend(vec![TokenTree::Ident(binder.clone())]),
+        }
+        if let Some(annotation) = annotation {
+            ts.extend(vec![TokenTree::Punct(Punct::new(':', Spacing::Alone))]);
+            ts.extend(annotation);
+        }
+        ts.extend(vec![TokenTree::Punct(Punct::new('=', Spacing::Alone))]);
+        ts.extend(dat.clone());
+        ts.extend(vec![TokenTree::Punct(Punct::new(';', Spacing::Alone))]);
+    }
+    if !is_recursed && ctx.is_validated {
+        ts.extend(code(
+            "let mut __multicall_failures__: ::std::vec::Vec<::std::string::String> =
+                ::std::vec::Vec::new();",
+        ));
+    }
+    // everything after this refers to the item as `__multicall_item__`
+    let head = ts;
+    let mut ts = TokenStream::new();
+    trace!("done. processing statements.");
+    if zipped.is_some() {
+        for (stmt, _) in &mut statements {
+            *stmt = zip_placeholders(core::mem::take(stmt));
+        }
+    }
+    if let Some(ref cleanup) = guard {
+        trace!("moving the item into a 'guard_drop' guard.");
+        ts.extend(guard_drop(cleanup.clone(), &ctx));
+    }
+    let mut cleanup = Vec::new();
+    let mut deferred = Vec::new();
+    for (mut stmt, terminated) in core::mem::take(&mut statements) {
+        match stmt.as_slice() {
+            [TokenTree::Ident(x), TokenTree::Group(g)]
+                if x.to_string() == "finally" && g.delimiter() == Delimiter::Brace =>
+            {
+                trace!("found 'finally' block. running it when the block is left.");
+                cleanup.extend(split_statements(g.stream().into_iter()));
+            }
+            [TokenTree::Ident(x), _, ..] if x.to_string() == "defer_in_block" => {
+                trace!("found 'defer_in_block'. running it when the block is left.");
+                deferred.push((stmt.split_off(1), true));
+            }
+            _ => statements.push((stmt, terminated)),
+        }
+    }
+    // deferred statements run in reverse order, after the `finally` block
+    cleanup.extend(deferred.into_iter().rev());
+    if !cleanup.is_empty() {
+        ts.extend(finally(cleanup, &ctx));
+    }
+    let mut body = Vec::new();
+    let mut tail = None;
+    if let Some((scrutinee, ignore_rest)) = dispatch {
+        body.extend(dispatch_arms(scrutinee, statements, &ctx, ignore_rest));
+    } else {
+        let is_yielding =
+            |stmt: &[TokenTree]| matches!(stmt, [TokenTree::Ident(x)] if x.to_string() == "yield");
+        if !is_recursed
+            && ctx.is_with
+            && !statements.last().is_some_and(|(stmt, _)| is_yielding(stmt))
+        {
+            trace!("block is 'with!'. ending it in 'yield'.");
+            if let Some((_, terminated)) = statements.last_mut() {
+                *terminated = true;
+            }
+            statements.push((code("yield"), false));
+        }
+        // a trailing `yield;` hands back the item even though it is followed by a `;`
+        if let Some((stmt, terminated)) = statements.last_mut() {
+            if is_yielding(stmt) {
+                trace!("block ends in 'yield'. evaluating to the item.");
+                *terminated = false;
+            }
+        }
+        for (stmt, terminated) in statements {
+            if let (Some(changes), Some(field)) = (&changes, assigned_field(&stmt)) {
+                trace!("statement assigns a field. recording whether it changed.");
+                body.extend(track_change(field, statement(stmt, &ctx), changes));
+                body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+            } else if terminated {
+                body.extend(collect_error(statement(stmt, &ctx), &ctx));
+                body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+            } else {
+                tail = Some(collect_error(statement(stmt, &ctx), &ctx));
+            }
+        }
+    }
+    if !is_recursed && ctx.is_validated {
+        if let Some(tail) = tail.take() {
+            body.extend(tail);
+            body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+        }
+        tail = Some(code(
+            "if __multicall_failures__.is_empty() {
+                ::core::result::Result::Ok(__multicall_item__)
+            } else {
+                ::core::result::Result::Err(__multicall_failures__)
+            }",
+        ));
+    }
+    if guard.is_some() {
+        if let Some(tail) = tail.take() {
+            body.extend(tail);
+            body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+        }
+        tail = Some(code("__multicall_guard_drop__"));
+    }
+    if is_nonempty {
+        let mut guarded = code("if !__multicall_item__.is_empty()");
+        guarded.push(TokenTree::Group(Group::new(
+            Delimiter::Brace,
+            TokenStream::from_iter(body.into_iter().chain(tail.into_iter().flatten())),
+        )));
+        body = Vec::new();
+        tail = Some(guarded);
+    }
+    ts.extend(body);
+    let mut epilogue = Vec::new();
+    if let Some(target) = timed {
+        epilogue.extend(target);
+        epilogue.extend(code("= __multicall_start__.elapsed();"));
+    }
+    match tail {
+        // keep the value of the last statement as the value of the block
+        Some(tail) if !epilogue.is_empty() => {
+            ts.extend(code("let __multicall_value__ ="));
+            ts.extend(tail);
+            ts.extend(code(";"));
+            ts.extend(epilogue);
+            ts.extend(code("__multicall_value__"));
+        }
+        tail => {
+            ts.extend(tail.into_iter().flatten());
+            ts.extend(epilogue);
+        }
+    }
+    if is_recursed || alias.is_some() {
+        let item = if is_projected {
+            TokenTree::Group(Group::new(
+                Delimiter::None,
+                TokenStream::from_iter(dat.clone()),
+            ))
+        } else {
+            TokenTree::Ident(binder.clone())
+        };
+        ts = ts.into_iter().map(|x| replace_item(x, &item)).collect();
+    }
+    if is_range {
+        trace!("sub-block header is a range. running the block for each element.");
+        let body = TokenTree::Group(Group::new(Delimiter::Brace, ts));
+        ts = TokenStream::from_iter(code("for"));
+        ts.extend([TokenTree::Ident(binder)]);
+        ts.extend(code("in"));
+        ts.extend(dat);
+        ts.extend([body]);
+    } else if let Some(other) = zipped {
+        let body = TokenTree::Group(Group::new(Delimiter::Brace, ts));
+        let mut pattern = vec![TokenTree::Ident(binder)];
+        pattern.extend(code(", __multicall_other__"));
+        ts = TokenStream::from_iter(code("for"));
+        ts.extend([TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from_iter(pattern),
+        ))]);
+        ts.extend(code("in ::core::iter::IntoIterator::into_iter"));
+        ts.extend([TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from_iter(dat),
+        ))]);
+        ts.extend(code(".zip"));
+        ts.extend([
+            TokenTree::Group(Group::new(
+                Delimiter::Parenthesis,
+                TokenStream::from_iter(other),
+            )),
+            body,
+        ]);
+    }
+    if let Some(mut words) = postprocess {
+        trace!("passing the statements to the 'postprocess' macro.");
+        words.push(TokenTree::Group(Group::new(Delimiter::Brace, ts)));
+        ts = TokenStream::from_iter(words);
+    }
+    trace!("multicall block done.");
+    let mut block = head;
+    block.extend(ts);
+    // `return_if` breaks out of the block, which needs a label for that
+    let label = block_label(&ctx);
+    if has_ident(block.clone(), &label[1].to_string()) {
+        let block = TokenTree::Group(Group::new(Delimiter::Brace, block));
+        let mut words = label;
+        words.push(TokenTree::Punct(Punct::new(':', Spacing::Alone)));
+        words.push(block);
+        return TokenStream::from_iter(words);
+    }
+    TokenStream::from(TokenTree::Group(Group::new(Delimiter::Brace, block)))
+}
+
+/// Returns whether the header starts with the keyword followed by an expression, as in
+/// `dispatch cmd:`, rather than with a variable of that name, as in `dispatch.len():`.
+fn has_prefix(header: &[TokenTree], keyword: &str) -> bool {
+    match header {
+        [TokenTree::Ident(x), next, ..] if x.to_string() == keyword => match next {
+            TokenTree::Ident(x) => x.to_string() != "as",
+            TokenTree::Literal(_) => true,
+            TokenTree::Group(g) => g.delimiter() != Delimiter::Bracket,
+            // a unary operator, but not a binary one, a field access or a path
+            TokenTree::Punct(p) => {
+                matches!(p.as_char(), '&' | '*' | '!') && p.spacing() == Spacing::Alone
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Returns whether a header is a mutable borrow: it starts with `&mut`, possibly inside
+/// parentheses.
+fn is_mut_borrow(header: &[TokenTree]) -> bool {
+    match header {
+        [x, r#mut, ..] => is_punct(x, '&') && r#mut.to_string() == "mut",
+        [TokenTree::Group(g)] if g.delimiter() == Delimiter::Parenthesis => {
+            is_mut_borrow(&g.stream().into_iter().collect::<Vec<_>>())
+        }
+        _ => false,
+    }
+}
+
+/// Returns the label of the block, which `return_if` breaks out of. Nested blocks get different
+/// ones, so they don't shadow each other.
+fn block_label(ctx: &Context) -> Vec<TokenTree> {
+    code(&format!("'__multicall_block_{}__", ctx.depth))
+}
+
+/// Returns whether the tokens, or any group in them, contain the identifier.
+fn has_ident(tokens: TokenStream, name: &str) -> bool {
+    tokens.into_iter().any(|x| match x {
+        TokenTree::Group(g) => has_ident(g.stream(), name),
+        TokenTree::Ident(x) => x.to_string() == name,
+        _ => false,
+    })
+}
+
+/// Returns whether `name` can only be a variable and not a type: it starts with a lowercase letter
+/// or `_` and isn't a primitive type.
+fn is_binding_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_lowercase() || c == '_')
+        && !matches!(
+            name,
+            "bool"
+                | "char"
+                | "str"
+                | "f32"
+                | "f64"
+                | "i8"
+                | "i16"
+                | "i32"
+                | "i64"
+                | "i128"
+                | "isize"
+                | "u8"
+                | "u16"
+                | "u32"
+                | "u64"
+                | "u128"
+                | "usize"
+        )
+}
+
+/// Returns whether a statement needs the item of a sub-block to be a reference, rather than the
+/// field path it was taken from: when it rebinds or dereferences the item, or uses `#` on its own.
+fn needs_borrow(tokens: &[TokenTree]) -> bool {
+    if matches!(
+        keyword(tokens).as_deref(),
+        Some("finally" | "defer_in_block" | "if_debug" | "reset" | "rebuild" | "with" | "yield")
+    ) {
+        return true;
+    }
+    tokens.iter().enumerate().any(|(i, x)| match x {
+        TokenTree::Group(g) => needs_borrow(&g.stream().into_iter().collect::<Vec<_>>()),
+        x if is_punct(x, '#') => !matches!(tokens.get(i + 1), Some(x)
+            if is_punct(x, '.')
+                || matches!(x, TokenTree::Group(g) if g.delimiter() == Delimiter::Bracket)
+                || matches!(x.to_string().as_str(), "ref" | "mut")),
+        _ => false,
+    })
+}
+
+/// Returns the field path an assignment like `a.b = x` or `a += x` assigns to, if the statement is
+/// one.
+fn assigned_field(tokens: &[TokenTree]) -> Option<Vec<TokenTree>> {
+    if keyword(tokens).is_some() {
+        return None;
+    }
+    let field = &tokens[..operator_start(tokens, find_assignment(tokens)?)];
+    let is_path = matches!(field.first(), Some(TokenTree::Ident(_)))
+        && field
+            .iter()
+            .all(|x| matches!(x, TokenTree::Ident(_) | TokenTree::Literal(_)) || is_punct(x, '.'));
+    is_path.then(|| field.to_vec())
+}
+
+/// For the `track_changes` directive, pushes the name of the field to `changes` if the assignment
+/// changed its value.
+fn track_change(
+    field: Vec<TokenTree>,
+    assignment: Vec<TokenTree>,
+    changes: &[TokenTree],
+) -> Vec<TokenTree> {
+    let name: String = field.iter().map(|x| x.to_string()).collect();
+    let value = operand(field);
+    let mut words = code("let __multicall_old__ = ::core::clone::Clone::clone");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(code("&").into_iter().chain(value.clone())),
+    )));
+    words.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    words.extend(assignment);
+    words.extend(code("; if __multicall_old__ !="));
+    words.extend(value);
+    let mut push = changes.to_vec();
+    push.extend(code(".push"));
+    push.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from(TokenTree::Literal(Literal::string(&name))),
+    )));
+    push.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(push),
+    )));
+    vec![TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(words),
+    ))]
+}
+
+/// Returns whether the tokens contain a statement starting with the keyword, including in nested
+/// groups like sub-blocks. Only keywords followed by an identifier, `#` or `!` are counted, to leave
+/// out method calls of the same name.
+fn has_keyword(tokens: &[TokenTree], keyword: &str) -> bool {
+    tokens.iter().enumerate().any(|(i, x)| match x {
+        TokenTree::Group(g) => has_keyword(&g.stream().into_iter().collect::<Vec<_>>(), keyword),
+        TokenTree::Ident(x) => {
+            x.to_string() == keyword
+                && matches!(tokens.get(i + 1), Some(x)
+                    if matches!(x, TokenTree::Ident(_)) || ['#', '!', '|'].iter().any(|&c| is_punct(x, c)))
+        }
+        _ => false,
+    })
+}
+
+/// Returns whether the tokens start with `|>`.
+fn is_pipe(tokens: &[TokenTree]) -> bool {
+    matches!(tokens, [TokenTree::Punct(p), x, ..]
+        if p.as_char() == '|' && p.spacing() == Spacing::Joint && is_punct(x, '>'))
+}
+
+/// Whether the tokens, or any group in them, borrow the item as `#mut`.
+fn has_item_mut(tokens: &[TokenTree]) -> bool {
+    tokens.iter().enumerate().any(|(i, x)| match x {
+        TokenTree::Group(g) => has_item_mut(&g.stream().into_iter().collect::<Vec<_>>()),
+        x => is_punct(x, '#') && tokens.get(i + 1).is_some_and(|x| x.to_string() == "mut"),
+    })
+}
+
+/// Returns whether the tokens contain a `|>` pipeline, including in sub-blocks. Like for `take`,
+/// the item then has to be bound mutably.
+fn has_pipe(tokens: &[TokenTree]) -> bool {
+    (0..tokens.len()).any(|i| match &tokens[i] {
+        TokenTree::Group(g) => has_pipe(&g.stream().into_iter().collect::<Vec<_>>()),
+        _ => is_pipe(&tokens[i..]),
+    })
+}
+
+/// `|> f(args) |> g();`: passes the item to the functions in turn, as their first argument, and
+/// assigns the result back to the item.
+fn pipe(tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[1].span();
+    let mut value = code("__multicall_item__");
+    let mut rest = tokens.as_slice();
+    while is_pipe(rest) {
+        rest = &rest[2..];
+        let end = (0..rest.len())
+            .find(|&i| is_pipe(&rest[i..]))
+            .unwrap_or(rest.len());
+        let (call, next) = rest.split_at(end);
+        let Some((TokenTree::Group(args), function)) = call.split_last() else {
+            return compile_error("multicall: expected `|> function(args)`", span);
+        };
+        if args.delimiter() != Delimiter::Parenthesis || function.is_empty() {
+            return compile_error("multicall: expected `|> function(args)`", span);
+        }
+        let mut inner = value;
+        if !args.stream().is_empty() {
+            inner.push(TokenTree::Punct(Punct::new(',', Spacing::Alone)));
+            inner.extend(
+                args.stream()
+                    .into_iter()
+                    .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+            );
+        }
+        value = function.to_vec();
+        let mut group = Group::new(Delimiter::Parenthesis, TokenStream::from_iter(inner));
+        group.set_span(args.span());
+        value.push(TokenTree::Group(group));
+        rest = next;
+    }
+    if !rest.is_empty() {
+        return compile_error("multicall: expected `|> function(args)`", span);
+    }
+    let mut words = code("__multicall_item__ =");
+    words.extend(value);
+    words
+}
+
+/// Replaces every `__multicall_item__` by `item`, which a sub-block uses to refer to its own item.
+fn replace_item(token: TokenTree, item: &TokenTree) -> TokenTree {
+    match token {
+        TokenTree::Group(x) => {
+            let mut g = Group::new(
+                x.delimiter(),
+                x.stream()
+                    .into_iter()
+                    .map(|x| replace_item(x, item))
+                    .collect(),
+            );
+            g.set_span(x.span());
+            TokenTree::Group(g)
+        }
+        TokenTree::Ident(x) if x.to_string() == "__multicall_item__" => item.clone(),
+        x => x,
+    }
+}
+
+/// Replaces the `#1` and `#2` placeholders of a `zip` block by the elements of the first and second
+/// collection. `#` keeps referring to the first one.
+fn zip_placeholders(tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut words = Vec::new();
+    let mut iter = tokens.into_iter().peekable();
+    while let Some(x) = iter.next() {
+        match x {
+            TokenTree::Group(g) => {
+                let mut group = Group::new(
+                    g.delimiter(),
+                    TokenStream::from_iter(zip_placeholders(g.stream().into_iter().collect())),
+                );
+                group.set_span(g.span());
+                words.push(TokenTree::Group(group));
+            }
+            x if is_punct(&x, '#') => match iter.peek().map(ToString::to_string).as_deref() {
+                Some("1") => {
+                    iter.next();
+                    words.push(x);
+                }
+                Some("2") => {
+                    iter.next();
+                    words.push(TokenTree::Ident(Ident::new(
+                        "__multicall_other__",
+                        x.span(),
+                    )));
+                }
+                _ => words.push(x),
+            },
+            x => words.push(x),
+        }
+    }
+    words
+}
+
+/// Creates a guard running the statements of a `finally` block and the `defer_in_block` statements
+/// on the item when it is dropped, and rebinds the item to borrow from it.
+fn finally(cleanup: Vec<(Vec<TokenTree>, bool)>, ctx: &Context) -> Vec<TokenTree> {
+    let mut words = code(
+        "struct __MulticallFinally__<'a, T: ?Sized, F: FnMut(&mut T)>(&'a mut T, F);
+        impl<'a, T: ?Sized, F: FnMut(&mut T)> __MulticallFinally__<'a, T, F> {
+            fn new(item: &'a mut T, cleanup: F) -> Self {
+                Self(item, cleanup)
+            }
+        }
+        impl<'a, T: ?Sized, F: FnMut(&mut T)> ::core::ops::Drop for __MulticallFinally__<'a, T, F> {
+            fn drop(&mut self) {
+                (self.1)(&mut *self.0)
+            }
+        }
+        let mut __multicall_finally__ = __MulticallFinally__::new",
+    );
+    let mut body = Vec::new();
+    for (stmt, _) in cleanup {
+        body.extend(statement(stmt, ctx));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    let mut args = code("__multicall_item__, |__multicall_item__|");
+    args.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(args),
+    )));
+    words.extend(code(
+        "; let __multicall_item__ = &mut *__multicall_finally__.0;",
+    ));
+    words
+}
+
+/// Moves the item into a guard running the statements of a `guard_drop` directive on it when it is
+/// dropped, and rebinds the item to borrow from it. The guard is what the block evaluates to.
+fn guard_drop(cleanup: TokenStream, ctx: &Context) -> Vec<TokenTree> {
+    let mut words = code(
+        "struct __MulticallGuard__<T, F: FnMut(&mut T)>(T, F);
+        impl<T, F: FnMut(&mut T)> __MulticallGuard__<T, F> {
+            fn new(item: T, cleanup: F) -> Self {
+                Self(item, cleanup)
+            }
+        }
+        impl<T, F: FnMut(&mut T)> ::core::ops::Deref for __MulticallGuard__<T, F> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.0
+            }
+        }
+        impl<T, F: FnMut(&mut T)> ::core::ops::DerefMut for __MulticallGuard__<T, F> {
+            fn deref_mut(&mut self) -> &mut T {
+                &mut self.0
+            }
+        }
+        impl<T, F: FnMut(&mut T)> ::core::ops::Drop for __MulticallGuard__<T, F> {
+            fn drop(&mut self) {
+                (self.1)(&mut self.0)
+            }
+        }
+        let mut __multicall_guard_drop__ = __MulticallGuard__::new",
+    );
+    let mut body = Vec::new();
+    for (stmt, _) in split_statements(cleanup.into_iter()) {
+        body.extend(statement(stmt, ctx));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    let mut args = code("__multicall_item__, |__multicall_item__|");
+    args.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(args),
+    )));
+    words.extend(code(
+        "; let __multicall_item__ = &mut __multicall_guard_drop__.0;",
+    ));
+    words
+}
+
+/// Returns whether an index contains a top-level `..`, meaning it selects a range of elements.
+fn is_range(index: &Group) -> bool {
+    let tokens: Vec<_> = index.stream().into_iter().collect();
+    tokens.windows(2).any(|x| {
+        matches!(&x[0], TokenTree::Punct(p) if p.as_char() == '.' && p.spacing() == Spacing::Joint)
+            && is_punct(&x[1], '.')
+    })
+}
+
+/// Splits the block body at its top-level `;`s. The flag tells whether the statement was
+/// terminated by one, which is only not the case for the last statement.
+///
+/// Invisible groups at the start of a statement, which `macro_rules!` creates when passing
+/// fragments like `$s:stmt`, are flattened so they aren't mistaken for sub-blocks.
+fn split_statements(iter: impl Iterator<Item = TokenTree>) -> Vec<(Vec<TokenTree>, bool)> {
+    let mut statements = Vec::new();
+    let mut stmt = Vec::new();
+    let mut pending: Vec<TokenTree> = iter.collect();
+    pending.reverse();
+    while let Some(x) = pending.pop() {
+        if let TokenTree::Group(ref g) = x {
+            if g.delimiter() == Delimiter::None && stmt.is_empty() {
+                trace!("flattening invisible group.");
+                pending.extend(g.stream().into_iter().collect::<Vec<_>>().into_iter().rev());
+                continue;
+            }
+        }
+        if is_punct(&x, ';') {
+            statements.push((stmt, true));
+            stmt = Vec::new();
+        } else {
+            stmt.push(x);
+        }
+    }
+    if !stmt.is_empty() {
+        statements.push((stmt, false));
+    }
+    statements
+}
+
+/// Generates the `match` for the `dispatch` and `transitions` directives, turning every
+/// `Pattern => operation;` statement into an arm. Values not matched by any of them are ignored if
+/// `ignore_rest` is set.
+fn dispatch_arms(
+    scrutinee: Vec<TokenTree>,
+    statements: Vec<(Vec<TokenTree>, bool)>,
+    ctx: &Context,
+    ignore_rest: bool,
+) -> Vec<TokenTree> {
+    let mut arms = Vec::new();
+    for (mut stmt, _) in statements {
+        let Some(arrow) = find_fat_arrow(&stmt) else {
+            let span = stmt.first().map_or_else(Span::call_site, TokenTree::span);
+            return compile_error(
+                "multicall: expected `Pattern => operation` in dispatch",
+                span,
+            );
+        };
+        let operation = stmt.split_off(arrow + 2);
+        stmt.truncate(arrow + 2);
+        arms.extend(stmt);
+        let mut body = collect_error(statement(operation, ctx), ctx);
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+        arms.push(TokenTree::Group(Group::new(
+            Delimiter::Brace,
+            TokenStream::from_iter(body),
+        )));
+    }
+    if ignore_rest {
+        arms.extend(code("#[allow(unreachable_patterns)] _ => {}"));
+    }
+    let mut words = vec![TokenTree::Ident(Ident::new("match", Span::call_site()))];
+    words.extend(scrutinee);
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(arms),
+    )));
+    words
+}
+
+/// Finds the index of the `=` of the first top-level `=>`.
+fn find_fat_arrow(tokens: &[TokenTree]) -> Option<usize> {
+    tokens.windows(2).position(|x| {
+        matches!(&x[0], TokenTree::Punct(p) if p.as_char() == '=' && p.spacing() == Spacing::Joint)
+            && is_punct(&x[1], '>')
+    })
+}
+
+/// Creates a block containing a `compile_error!` with the given message, underlining `span`.
+fn compile_error(message: &str, span: Span) -> Vec<TokenTree> {
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut message = Literal::string(message);
+    message.set_span(span);
+    let mut group = Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from(TokenTree::Literal(message)),
+    );
+    group.set_span(span);
+    let mut block = Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter([
+            TokenTree::Ident(Ident::new("compile_error", span)),
+            TokenTree::Punct(bang),
+            TokenTree::Group(group),
+        ]),
+    );
+    block.set_span(span);
+    vec![TokenTree::Group(block)]
+}
+
+/// Reads tokens up to the next `:` that is not part of a `::` path separator. Returns `None` if
+/// there is no such `:`.
+fn read_header(iter: &mut impl Iterator<Item = TokenTree>) -> Option<Vec<TokenTree>> {
+    let mut header: Vec<TokenTree> = Vec::new();
+    let mut found = false;
+    for item in iter.by_ref() {
+        if let TokenTree::Punct(ref x) = item {
+            let after_joint_colon = matches!(
+                header.last(),
+                Some(TokenTree::Punct(p)) if p.as_char() == ':' && p.spacing() == Spacing::Joint
+            );
+            if x.as_char() == ':' && x.spacing() == Spacing::Alone && !after_joint_colon {
+                found = true;
+                break;
+            }
+        }
+        header.push(item);
+    }
+    if !found {
+        return None;
+    }
+    // an expression passed in by `macro_rules!` arrives as a single invisible group
+    match header.as_slice() {
+        [TokenTree::Group(g)] if g.delimiter() == Delimiter::None => {
+            Some(g.stream().into_iter().collect())
+        }
+        _ => Some(header),
+    }
+}
+
+/// Parses a fixed piece of generated code.
+fn code(src: &str) -> Vec<TokenTree> {
+    src.parse::<TokenStream>().unwrap().into_iter().collect()
+}
+
+fn is_punct(token: &TokenTree, c: char) -> bool {
+    matches!(token, TokenTree::Punct(x) if x.as_char() == c)
+}
+
+/// Finds the `=` of the first top-level assignment (`=`, `+=`, `<<=`, ...), as opposed to a
+/// comparison like `==` or `<=` or a `=>`.
+fn find_assignment(tokens: &[TokenTree]) -> Option<usize> {
+    let joint = |i: Option<usize>| match i.map(|i| &tokens[i]) {
+        Some(TokenTree::Punct(p)) if p.spacing() == Spacing::Joint => Some(p.as_char()),
+        _ => None,
+    };
+    (0..tokens.len()).find(|&i| {
+        is_punct(&tokens[i], '=')
+            && (joint(Some(i)).is_none()
+                || !matches!(tokens.get(i + 1), Some(x) if is_punct(x, '=') || is_punct(x, '>')))
+            && match joint(i.checked_sub(1)) {
+                Some('=' | '!') => false,
+                Some(c @ ('<' | '>')) => joint(i.checked_sub(2)) == Some(c),
+                _ => true,
+            }
+    })
+}
+
+/// Finds the first `:` that is not part of a `::` path separator.
+fn find_colon(tokens: &[TokenTree]) -> Option<usize> {
+    let is_colon = |x: &TokenTree, spacing| matches!(x, TokenTree::Punct(p) if p.as_char() == ':' && p.spacing() == spacing);
+    (1..tokens.len()).find(|&i| {
+        is_colon(&tokens[i], Spacing::Alone) && !is_colon(&tokens[i - 1], Spacing::Joint)
+    })
+}
+
+/// Returns where the assignment operator whose `=` is at `eq` starts, like the `+` of `+=`.
+fn operator_start(tokens: &[TokenTree], mut eq: usize) -> usize {
+    while matches!(tokens[..eq].last(), Some(TokenTree::Punct(p)) if p.spacing() == Spacing::Joint)
+    {
+        eq -= 1;
+    }
+    eq
+}
+
+/// Creates the `__multicall_item__.` that a statement starting at `span` is prefixed with. It is
+/// located at the statement, so errors point there, but resolves like the rest of the generated
+/// code.
+fn item_at(span: Span) -> [TokenTree; 2] {
+    let span = Span::call_site().located_at(span);
+    let mut dot = Punct::new('.', Spacing::Alone);
+    dot.set_span(span);
+    [
+        TokenTree::Ident(Ident::new("__multicall_item__", span)),
+        TokenTree::Punct(dot),
+    ]
+}
+
+/// Prefixes an expression with the item unless it already starts with `#`, like the value of a
+/// `set`.
+fn operand(tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut words = Vec::new();
+    match tokens.first() {
+        Some(x) if is_punct(x, '#') => {}
+        Some(x) => words.extend(item_at(x.span())),
+        None => words.extend(item_at(Span::call_site())),
+    }
+    words.extend(
+        tokens
+            .into_iter()
+            .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+    );
+    words
+}
+
+/// Returns the keyword a statement starts with. A keyword is followed by an identifier, a
+/// literal, `#`, `|`, a label or a group, or ends the statement. Identifiers followed by a call,
+/// an index or an operator are not keywords, since they are meant as operations on the item.
+fn keyword(tokens: &[TokenTree]) -> Option<String> {
+    match tokens {
+        [TokenTree::Ident(x), rest @ ..] => match rest.first() {
+            // a parenthesized argument followed by another identifier or a block can't be a call
+            Some(TokenTree::Group(g))
+                if g.delimiter() == Delimiter::Parenthesis
+                    && !matches!(rest.get(1), Some(TokenTree::Ident(_)))
+                    && !matches!(rest.get(1), Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace) =>
+            {
+                None
+            }
+            Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Bracket => None,
+            Some(TokenTree::Punct(p)) => match p.as_char() {
+                '#' | '|' | '\'' => Some(x.to_string()),
+                // a unary operator may start the expression of a keyword, but not a compound
+                // assignment like `*=`
+                '*' | '-' | '&'
+                    if p.spacing() == Spacing::Alone
+                        && matches!(
+                            x.to_string().as_str(),
+                            "if" | "match" | "require" | "return_if" | "repeat"
+                        ) =>
+                {
+                    Some(x.to_string())
+                }
+                _ => None,
+            },
+            _ => Some(x.to_string()),
+        },
+        _ => None,
+    }
+}
+
+/// `set_some target = value;`: assigns only if the value is `Some`.
+fn set_some(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let Some(eq) = find_assignment(&tokens) else {
+        return compile_error("multicall: expected `set_some target = value`", span);
+    };
+    let value = tokens.split_off(eq + 1);
+    let mut words = code("if let ::core::option::Option::Some(__multicall_value__) =");
+    words.extend(operand(value));
+    let mut body: Vec<TokenTree> = tokens
+        .into_iter()
+        .skip(1)
+        .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+        .collect();
+    body.extend(code("__multicall_value__;"));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// `match_set target = { arms } on scrutinee;`: assigns the result of a `match` on the scrutinee.
+fn match_set(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let on = tokens.iter().rposition(|x| x.to_string() == "on");
+    let eq = match (find_assignment(&tokens), on) {
+        (Some(eq), Some(on))
+            if on == eq + 2
+                && matches!(&tokens[eq + 1], TokenTree::Group(g) if g.delimiter() == Delimiter::Brace) =>
+        {
+            eq
+        }
+        _ => {
+            return compile_error(
+                "multicall: expected `match_set target = { arms } on scrutinee`",
+                span,
+            )
+        }
+    };
+    let on = eq + 2;
+    let scrutinee = operand(tokens.split_off(on + 1));
+    let arms = recursive_replace(tokens.remove(eq + 1), "#", "__multicall_item__");
+    let mut words: Vec<TokenTree> = tokens
+        .into_iter()
+        .take(eq + 1)
+        .skip(1)
+        .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+        .collect();
+    words.push(TokenTree::Ident(Ident::new("match", span)));
+    words.extend(scrutinee);
+    words.push(arms);
+    words
+}
+
+/// Turns a single statement (without its terminating `;`) into the code operating on the item.
+fn statement(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    if let [.., q, TokenTree::Ident(m), TokenTree::Group(g)] = tokens.as_slice() {
+        if is_punct(q, '?') && m.to_string() == "map_err" && g.delimiter() == Delimiter::Parenthesis
+        {
+            trace!("statement ends in 'map_err'. converting the error before propagating it.");
+            let mut suffix = tokens.split_off(tokens.len() - 3);
+            suffix[0] = TokenTree::Punct(Punct::new('.', Spacing::Alone));
+            let converter = suffix.pop().unwrap();
+            suffix.push(recursive_replace(converter, "#", "__multicall_item__"));
+            suffix.push(TokenTree::Punct(Punct::new('?', Spacing::Alone)));
+            let mut words = statement(tokens, ctx);
+            words.extend(suffix);
+            return words;
+        }
+    }
+    if is_pipe(&tokens) {
+        trace!("statement is a '|>' pipeline. assigning the result back to the item.");
+        return pipe(tokens);
+    }
+    match keyword(&tokens).as_deref() {
+        Some("set_some") => set_some(tokens),
+        Some("match_set") => match_set(tokens),
+        Some("try_op") => try_op(tokens, ctx),
+        Some("toggle") => toggle(tokens),
+        Some("retry") => retry(tokens, ctx),
+        Some("require") => require(tokens),
+        Some("return_if") => return_if(tokens, ctx),
+        Some("ensure_range") => ensure_range(tokens),
+        Some("repeat") => repeat(tokens, ctx),
+        Some("if") => if_else(tokens, ctx),
+        Some("for") => for_loop(tokens, ctx),
+        Some("match") => match_arms(tokens, ctx),
+        Some("break" | "continue") => {
+            trace!("statement is 'break' or 'continue'. replaying it as is.");
+            tokens
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+                .collect()
+        }
+        Some("if_debug") => if_debug(tokens, ctx),
+        Some("batch") => batch(tokens),
+        Some("when_variant") => when_variant(tokens, ctx),
+        Some("snapshot") if tokens.len() >= 2 => {
+            trace!("statement is 'snapshot'. pushing a clone of the item.");
+            let mut words: Vec<TokenTree> = tokens.into_iter().skip(1).collect();
+            words.extend(code(".push(__multicall_item__.clone())"));
+            words
+        }
+        Some("share") if tokens.len() >= 3 && tokens[1].to_string() == "into" => {
+            trace!("statement is 'share'. pushing a new handle to the item.");
+            let mut words: Vec<TokenTree> = tokens.into_iter().skip(2).collect();
+            // method call syntax clones an owned handle and dereferences a borrowed one
+            words.extend(code(".push(__multicall_item__.clone())"));
+            words
+        }
+        Some("extend") => extend(tokens),
+        Some("push_all") => push_all(tokens),
+        Some("assign") => assign(tokens),
+        Some("check") => check(tokens, ctx),
+        Some("call") => {
+            trace!("statement is 'call'. making sure it is a method call.");
+            let mut call = tokens.split_off(1);
+            if call.len() == 1 {
+                call.push(TokenTree::Group(Group::new(
+                    Delimiter::Parenthesis,
+                    TokenStream::new(),
+                )));
+            }
+            operation(call, ctx)
+        }
+        Some("yield") if tokens.len() == 1 => code("__multicall_item__"),
+        Some("raw") => {
+            trace!("statement is 'raw'. replaying it untouched.");
+            tokens.split_off(1)
+        }
+        Some("rebuild") => rebuild(tokens),
+        Some("try") if tokens.len() >= 2 => {
+            trace!("statement is 'try'. propagating its error.");
+            let mut words = operation(tokens.split_off(1), ctx);
+            words.push(TokenTree::Punct(Punct::new('?', Spacing::Alone)));
+            words
+        }
+        Some("take") if tokens.len() >= 2 => {
+            trace!("statement is 'take'. assigning the result back to the item.");
+            let mut words = code("__multicall_item__ =");
+            words.extend(operation(tokens.split_off(1), ctx));
+            words
+        }
+        Some("drop") if tokens.len() >= 2 => {
+            trace!("statement is 'drop'. discarding its result.");
+            let mut words = code("let _ =");
+            words.extend(operation(tokens.split_off(1), ctx));
+            words
+        }
+        Some("reset") if tokens.len() == 1 => {
+            trace!("statement is 'reset'. assigning the default value.");
+            code("*__multicall_item__ = ::core::default::Default::default()")
+        }
+        Some(op @ ("inc" | "dec")) => {
+            let op = if op == "inc" { '+' } else { '-' };
+            increment(tokens, op)
+        }
+        _ => operation(tokens, ctx),
+    }
+}
+
+/// `rebuild |old| value;`: replaces the item by the value, which may use the old item as `old`.
+fn rebuild(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let end = tokens
+        .iter()
+        .skip(2)
+        .position(|x| is_punct(x, '|'))
+        .map(|x| x + 2);
+    let Some(end) = end.filter(|&end| is_punct(&tokens[1], '|') && end + 1 < tokens.len()) else {
+        return compile_error("multicall: expected `rebuild |old| value`", span);
+    };
+    let value = tokens.split_off(end + 1);
+    tokens.truncate(end);
+    let mut body = code("let");
+    body.extend(tokens.drain(2..));
+    body.extend(code("= __multicall_item__;"));
+    body.extend(
+        value
+            .into_iter()
+            .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+    );
+    let mut words = code("__multicall_item__ =");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// `if condition { ... } else { ... };`: runs the statements in the first or the second braces,
+/// depending on the condition. `else if` chains work the same way.
+fn if_else(tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let error = || {
+        compile_error(
+            "multicall: expected `if condition { ... } else { ... }`",
+            span,
+        )
+    };
+    let mut words = Vec::new();
+    let mut iter = tokens.into_iter();
+    loop {
+        // the condition is replayed up to the braces
+        let block = loop {
+            match iter.next() {
+                Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => break g,
+                Some(x) => words.push(recursive_replace(x, "#", "__multicall_item__")),
+                None => return error(),
+            }
+        };
+        words.push(statement_block(block, ctx));
+        match iter.next() {
+            None => return words,
+            Some(TokenTree::Ident(x)) if x.to_string() == "else" => words.push(TokenTree::Ident(x)),
+            Some(_) => return error(),
+        }
+        match iter.next() {
+            Some(TokenTree::Ident(x)) if x.to_string() == "if" => words.push(TokenTree::Ident(x)),
+            Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => {
+                if iter.next().is_some() {
+                    return error();
+                }
+                words.push(statement_block(g, ctx));
+                return words;
+            }
+            _ => return error(),
+        }
+    }
+}
+
+/// `match scrutinee { Pattern => operation, Pattern => { ... } };`: runs the operation or the
+/// statements of the matching arm.
+fn match_arms(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let error = || {
+        compile_error(
+            "multicall: expected `match scrutinee { Pattern => operation }`",
+            span,
+        )
+    };
+    let arms = match tokens.pop() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace && tokens.len() >= 2 => g,
+        _ => return error(),
+    };
+    let mut words: Vec<TokenTree> = tokens
+        .into_iter()
+        .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+        .collect();
+    let mut body = Vec::new();
+    let mut rest: Vec<TokenTree> = arms.stream().into_iter().collect();
+    while !rest.is_empty() {
+        // the pattern and its guard are replayed up to the `=>`
+        let Some(arrow) = find_fat_arrow(&rest) else {
+            return error();
+        };
+        let mut arm = rest.split_off(arrow + 2);
+        body.extend(
+            rest.into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        );
+        let end = match arm.first() {
+            Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace => 1,
+            _ => arm
+                .iter()
+                .position(|x| is_punct(x, ','))
+                .unwrap_or(arm.len()),
+        };
+        rest = arm.split_off(end);
+        if rest.first().is_some_and(|x| is_punct(x, ',')) {
+            rest.remove(0);
+        }
+        match arm.pop() {
+            Some(TokenTree::Group(g)) if arm.is_empty() && g.delimiter() == Delimiter::Brace => {
+                body.push(statement_block(g, ctx));
+            }
+            Some(x) => {
+                arm.push(x);
+                let mut operation = collect_error(statement(arm, ctx), ctx);
+                operation.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+                body.push(TokenTree::Group(Group::new(
+                    Delimiter::Brace,
+                    TokenStream::from_iter(operation),
+                )));
+            }
+            None => return error(),
+        }
+    }
+    let mut group = Group::new(Delimiter::Brace, TokenStream::from_iter(body));
+    group.set_span(arms.span());
+    words.push(TokenTree::Group(group));
+    words
+}
+
+/// `for x in iter { ... };`: runs the statements in the braces on every element.
+fn for_loop(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let (block, var) = match (tokens.pop(), tokens.get(1..3)) {
+        (Some(TokenTree::Group(block)), Some([TokenTree::Ident(var), r#in]))
+            if block.delimiter() == Delimiter::Brace
+                && r#in.to_string() == "in"
+                && tokens.len() > 3 =>
+        {
+            (block, TokenTree::Ident(var.clone()))
+        }
+        _ => return compile_error("multicall: expected `for variable in iter { ... }`", span),
+    };
+    let mut words: Vec<TokenTree> = tokens
+        .into_iter()
+        .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+        .collect();
+    // the statements work on the loop variable instead of the item
+    words.push(replace_item(statement_block(block, ctx), &var));
+    words
+}
+
+/// Turns the statements in braces into a block running them on the item.
+fn statement_block(block: Group, ctx: &Context) -> TokenTree {
+    let mut body = Vec::new();
+    for (stmt, _) in split_statements(block.stream().into_iter()) {
+        body.extend(collect_error(statement(stmt, ctx), ctx));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    let mut group = Group::new(Delimiter::Brace, TokenStream::from_iter(body));
+    group.set_span(block.span());
+    TokenTree::Group(group)
+}
+
+/// `repeat count { ... };`: runs the statements in the braces `count` times.
+fn repeat(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let Some(TokenTree::Group(block)) = tokens.pop() else {
+        return compile_error("multicall: expected `repeat count { ... }`", span);
+    };
+    if block.delimiter() != Delimiter::Brace || tokens.len() < 2 {
+        return compile_error("multicall: expected `repeat count { ... }`", span);
+    }
+    let mut body = Vec::new();
+    for (stmt, _) in split_statements(block.stream().into_iter()) {
+        body.extend(collect_error(statement(stmt, ctx), ctx));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    let mut words = code("for _ in 0..");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            tokens
+                .into_iter()
+                .skip(1)
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    )));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// `batch { a = x; b = y; };`: evaluates all assigned values before assigning any of them.
+fn batch(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let block = match tokens.pop() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace && tokens.len() == 1 => g,
+        _ => return compile_error("multicall: expected `batch { ... }`", span),
+    };
+    let mut values = Vec::new();
+    let mut writes = Vec::new();
+    for (i, (mut stmt, _)) in split_statements(block.stream().into_iter())
+        .into_iter()
+        .enumerate()
+    {
+        let Some(eq) = find_assignment(&stmt) else {
+            return compile_error("multicall: `batch` may only contain assignments", span);
+        };
+        let temp = TokenTree::Ident(Ident::new(
+            &format!("__multicall_batch_{i}__"),
+            Span::call_site(),
+        ));
+        values.extend(code("let"));
+        values.push(temp.clone());
+        values.push(TokenTree::Punct(Punct::new('=', Spacing::Alone)));
+        values.extend(
+            stmt.split_off(eq + 1)
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        );
+        values.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+        let op = stmt.split_off(operator_start(&stmt, eq));
+        writes.extend(operand(stmt));
+        writes.extend(op);
+        writes.push(temp);
+        writes.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    values.extend(writes);
+    vec![TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(values),
+    ))]
+}
+
+/// `when_variant Pattern { ... };`: runs the statements in the braces if the item matches the
+/// pattern.
+fn when_variant(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let block = match tokens.pop() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace && tokens.len() >= 2 => g,
+        _ => return compile_error("multicall: expected `when_variant Pattern { ... }`", span),
+    };
+    let mut body = Vec::new();
+    for (stmt, _) in split_statements(block.stream().into_iter()) {
+        body.extend(collect_error(statement(stmt, ctx), ctx));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    let mut words = code("if let");
+    words.extend(tokens.into_iter().skip(1));
+    words.extend(code("= __multicall_item__"));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// `if_debug { ... };`: runs the statements in the braces on the item as a `&dyn Debug`, if its type
+/// implements `Debug`.
+///
+/// This uses autoref specialization: the method on `Wrap<T>`, which requires `T: Debug`, is found
+/// before the fallback on `&Wrap<T>`, which needs one more autoref.
+fn if_debug(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let block = match tokens.pop() {
+        Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Brace && tokens.len() == 1 => g,
+        _ => return compile_error("multicall: expected `if_debug { ... }`", span),
+    };
+    let mut body = Vec::new();
+    for (stmt, _) in split_statements(block.stream().into_iter()) {
+        body.extend(collect_error(statement(stmt, ctx), ctx));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    let mut words = code(
+        "struct __MulticallDebug__<'a, T: ?Sized>(&'a T);
+        trait __MulticallViaDebug__ {
+            fn __multicall_debug__(&self) -> ::core::option::Option<&dyn ::core::fmt::Debug>;
+        }
+        impl<'a, T: ::core::fmt::Debug> __MulticallViaDebug__ for __MulticallDebug__<'a, T> {
+            fn __multicall_debug__(&self) -> ::core::option::Option<&dyn ::core::fmt::Debug> {
+                ::core::option::Option::Some(self.0)
+            }
+        }
+        trait __MulticallViaNone__ {
+            fn __multicall_debug__(&self) -> ::core::option::Option<&dyn ::core::fmt::Debug> {
+                ::core::option::Option::None
+            }
+        }
+        impl<'a, T: ?Sized> __MulticallViaNone__ for &__MulticallDebug__<'a, T> {}
+        if let ::core::option::Option::Some(__multicall_item__) =
+            (&__MulticallDebug__(&__multicall_item__)).__multicall_debug__()",
+    );
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    vec![TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(words),
+    ))]
+}
+
+/// `extend field from iter;`: extends a collection field of the item.
+fn extend(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let Some(from) = tokens.iter().position(|x| x.to_string() == "from") else {
+        return compile_error(
+            "multicall: expected `extend field from iter`",
+            tokens[0].span(),
+        );
+    };
+    let iter = tokens.split_off(from + 1);
+    tokens.truncate(from);
+    let mut words = operand(tokens.split_off(1));
+    words.extend(code(".extend"));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            iter.into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    )));
+    words
+}
+
+/// `push_all field: [a, b, c];`: pushes several values to a collection field of the item.
+fn push_all(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let values = match tokens.as_slice() {
+        [.., colon, TokenTree::Group(g)]
+            if is_punct(colon, ':') && g.delimiter() == Delimiter::Bracket =>
+        {
+            g.stream()
+        }
+        _ => return compile_error("multicall: expected `push_all field: [values]`", span),
+    };
+    tokens.truncate(tokens.len() - 2);
+    let field = operand(tokens.split_off(1));
+    let mut body = Vec::new();
+    let mut values: Vec<TokenTree> = values
+        .into_iter()
+        .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+        .collect();
+    while !values.is_empty() {
+        let rest = match values.iter().position(|x| is_punct(x, ',')) {
+            Some(comma) => values.split_off(comma + 1),
+            None => Vec::new(),
+        };
+        if values.last().is_some_and(|x| is_punct(x, ',')) {
+            values.pop();
+        }
+        body.extend(field.clone());
+        body.extend(code(".push"));
+        body.push(TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from_iter(values),
+        )));
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+        values = rest;
+    }
+    vec![TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    ))]
+}
+
+/// `assign a, b from source;`: copies the listed fields of the source into the item.
+fn assign(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let Some(from) = tokens.iter().position(|x| x.to_string() == "from") else {
+        return compile_error("multicall: expected `assign fields from source`", span);
+    };
+    let source = TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            tokens
+                .split_off(from + 1)
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    ));
+    tokens.truncate(from);
+    let mut body = Vec::new();
+    for field in tokens.split_off(1).split(|x| is_punct(x, ',')) {
+        if field.is_empty() {
+            continue;
+        }
+        body.extend(operand(field.to_vec()));
+        body.push(TokenTree::Punct(Punct::new('=', Spacing::Alone)));
+        body.push(source.clone());
+        body.push(TokenTree::Punct(Punct::new('.', Spacing::Alone)));
+        body.extend(field.iter().cloned());
+        body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    }
+    vec![TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    ))]
+}
+
+/// `check condition: message;`: records the message as a failure of a `validate` item unless the
+/// condition holds.
+fn check(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    if !ctx.is_validated {
+        return compile_error("multicall: `check` requires a `validate` item", span);
+    }
+    let Some(colon) = find_colon(&tokens).filter(|&i| i > 1 && i + 1 < tokens.len()) else {
+        return compile_error("multicall: expected `check condition: message`", span);
+    };
+    let message = tokens.split_off(colon + 1);
+    tokens.truncate(colon);
+    let mut words = code("if !");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(operand(tokens.split_off(1))),
+    )));
+    let mut failure = code("::std::string::String::from");
+    failure.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            message
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    )));
+    let mut body = code("__multicall_failures__.push");
+    body.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(failure),
+    )));
+    body.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// `ensure_range field in lo..=hi clamp;`, `ensure_range field in range else error;`: clamps a
+/// field of the item to the range, or returns the error unless it is in the range.
+fn ensure_range(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let Some(at) = tokens
+        .iter()
+        .position(|x| x.to_string() == "in")
+        .filter(|&at| at > 1)
+    else {
+        return compile_error(
+            "multicall: expected `ensure_range field in range ...`",
+            span,
+        );
+    };
+    let mut range = tokens.split_off(at + 1);
+    tokens.truncate(at);
+    let field = operand(tokens.split_off(1));
+    if range.last().is_some_and(|x| x.to_string() == "clamp") {
+        range.pop();
+        // `clamp` needs both bounds, so the range has to be `lo..=hi`
+        let is_joint_dot = |x: &TokenTree| matches!(x, TokenTree::Punct(p) if p.as_char() == '.' && p.spacing() == Spacing::Joint);
+        let Some(dots) = range
+            .windows(3)
+            .position(|x| is_joint_dot(&x[0]) && is_joint_dot(&x[1]) && is_punct(&x[2], '='))
+        else {
+            return compile_error("multicall: `clamp` expects a range like `lo..=hi`", span);
+        };
+        let hi = range.split_off(dots + 3);
+        range.truncate(dots);
+        let mut words = field.clone();
+        words.push(TokenTree::Punct(Punct::new('=', Spacing::Alone)));
+        words.extend(field);
+        words.extend(code(".clamp"));
+        let mut bounds = range;
+        bounds.push(TokenTree::Punct(Punct::new(',', Spacing::Alone)));
+        bounds.extend(hi);
+        words.push(TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from_iter(
+                bounds
+                    .into_iter()
+                    .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+            ),
+        )));
+        return words;
+    }
+    let Some(at) = range.iter().rposition(|x| x.to_string() == "else") else {
+        return compile_error(
+            "multicall: expected `ensure_range field in range clamp` or `... else error`",
+            span,
+        );
+    };
+    let error = range.split_off(at + 1);
+    range.truncate(at);
+    let mut condition = vec![TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            range
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    ))];
+    condition.extend(code(".contains"));
+    condition.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(code("&").into_iter().chain(field)),
+    )));
+    return_unless(condition, error)
+}
+
+/// `return_if condition: value;`: makes the block evaluate to the value if the condition holds,
+/// skipping the rest of it.
+fn return_if(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let Some(colon) = find_colon(&tokens).filter(|&i| i > 1 && i + 1 < tokens.len()) else {
+        return compile_error("multicall: expected `return_if condition: value`", span);
+    };
+    let value = tokens.split_off(colon + 1);
+    tokens.truncate(colon);
+    let mut words = code("if");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(operand(tokens.split_off(1))),
+    )));
+    let mut body = code("break");
+    body.extend(block_label(ctx));
+    body.extend(
+        value
+            .into_iter()
+            .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+    );
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// `require condition else error;`: returns the error, converted with `.into()`, unless the
+/// condition holds.
+fn require(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    let Some(at) = tokens.iter().rposition(|x| x.to_string() == "else") else {
+        return compile_error("multicall: expected `require condition else error`", span);
+    };
+    let error = tokens.split_off(at + 1);
+    tokens.truncate(at);
+    return_unless(operand(tokens.split_off(1)), error)
+}
+
+/// Returns `Err(error.into())` from the surrounding function unless the condition holds.
+fn return_unless(condition: Vec<TokenTree>, error: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut words = code("if !");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(condition),
+    )));
+    let mut body = code("return ::core::result::Result::Err");
+    let mut error = vec![TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            error
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    ))];
+    error.extend(code(".into()"));
+    body.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(error),
+    )));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(body),
+    )));
+    words
+}
+
+/// For the `collect_errors` directive, turns a statement ending in `?` into one pushing the error
+/// into the collection instead of returning it.
+fn collect_error(mut words: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let Some(ref errors) = ctx.errors else {
+        return words;
+    };
+    if !words.last().is_some_and(|x| is_punct(x, '?')) || find_assignment(&words).is_some() {
+        return words;
+    }
+    trace!("statement is fallible. collecting its error.");
+    words.pop();
+    let mut collected = code("if let ::core::result::Result::Err(__multicall_error__) =");
+    collected.extend(words);
+    let mut push = errors.clone();
+    push.extend(code(".push(__multicall_error__);"));
+    collected.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(push),
+    )));
+    collected
+}
+
+/// `inc field;`, `dec field by step;`: adds to or subtracts from a field of the item.
+fn increment(mut tokens: Vec<TokenTree>, op: char) -> Vec<TokenTree> {
+    let step = match tokens.iter().position(|x| x.to_string() == "by") {
+        Some(by) => {
+            let step = tokens.split_off(by + 1);
+            tokens.truncate(by);
+            step.into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+                .collect()
+        }
+        None => vec![TokenTree::Literal(Literal::u8_unsuffixed(1))],
+    };
+    let mut words = operand(tokens.split_off(1));
+    words.push(TokenTree::Punct(Punct::new(op, Spacing::Joint)));
+    words.push(TokenTree::Punct(Punct::new('=', Spacing::Alone)));
+    words.extend(step);
+    words
+}
+
+/// `retry count operation;`: runs a fallible operation up to `count` times, propagating the last
+/// error if none of them succeeded.
+fn retry(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    if tokens.len() < 3 {
+        return compile_error(
+            "multicall: expected `retry count operation`",
+            tokens[0].span(),
+        );
+    }
+    let operation = operation(tokens.split_off(2), ctx);
+    let count = match recursive_replace(tokens.pop().unwrap(), "#", "__multicall_item__") {
+        TokenTree::Group(g) if g.delimiter() == Delimiter::Parenthesis => g.stream(),
+        count => TokenStream::from(count),
+    };
+    let mut body = code("let __multicall_attempts__ =");
+    body.extend(count);
+    body.extend(code("; let mut __multicall_attempt__ = 1; loop"));
+    let mut arms = code("match");
+    arms.extend(operation);
+    arms.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(code(
+            "::core::result::Result::Ok(v) => break ::core::result::Result::Ok(v),
+            ::core::result::Result::Err(e) if __multicall_attempt__ >= __multicall_attempts__ => {
+                break ::core::result::Result::Err(e)
+            }
+            ::core::result::Result::Err(_) => __multicall_attempt__ += 1,",
+        )),
+    )));
+    body.push(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        TokenStream::from_iter(arms),
+    )));
+    vec![
+        TokenTree::Group(Group::new(Delimiter::Brace, TokenStream::from_iter(body))),
+        TokenTree::Punct(Punct::new('?', Spacing::Alone)),
+    ]
+}
+
+/// `toggle field;`: negates a field of the item.
+fn toggle(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let field = tokens.split_off(1);
+    let mut words = operand(field.clone());
+    words.push(TokenTree::Punct(Punct::new('=', Spacing::Alone)));
+    words.push(TokenTree::Punct(Punct::new('!', Spacing::Alone)));
+    words.extend(operand(field));
+    words
+}
+
+/// `try_op operation or default;`: evaluates to `default` if the operation panics.
+fn try_op(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let span = tokens[0].span();
+    if cfg!(not(feature = "std")) {
+        return compile_error("multicall: `try_op` requires the `std` feature", span);
+    }
+    let Some(or) = tokens.iter().rposition(|x| x.to_string() == "or") else {
+        return compile_error("multicall: expected `try_op operation or default`", span);
+    };
+    let default = tokens.split_off(or + 1);
+    tokens.truncate(or);
+    tokens.remove(0);
+    let closure = [
+        TokenTree::Punct(Punct::new('|', Spacing::Joint)),
+        TokenTree::Punct(Punct::new('|', Spacing::Alone)),
+    ]
+    .into_iter()
+    .chain(operation(tokens, ctx));
+    let mut words = code("::std::panic::catch_unwind");
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(code("::std::panic::AssertUnwindSafe").into_iter().chain([
+            TokenTree::Group(Group::new(
+                Delimiter::Parenthesis,
+                TokenStream::from_iter(closure),
+            )),
+        ])),
+    )));
+    words.extend(code(".unwrap_or"));
+    words.push(TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            default
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        ),
+    )));
+    words
+}
+
+/// Handles plain operations on the item, sub-calls, `set` and `exec`.
+fn operation(mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    #[derive(Default, PartialEq, Eq)]
+    #[cfg_attr(feature = "trace", derive(Debug))]
+    enum State {
+        #[default]
+        InsertNew,
+        Set,
+        /// Right after the `=` of a `set`, where parentheses or brackets are part of the value.
+        Value,
+        Inserted,
+    }
+    #[derive(Default)]
+    struct AccumState {
+        words: Vec<TokenTree>,
+        state: State,
+        /// Whether the statement is a plain operation on the item (not `set`, `exec` or a sub-call).
+        is_operation: bool,
+        /// Whether the target of a `set` is a tuple, slice or struct pattern.
+        is_pattern: bool,
+    }
+    tokens = item_refs(tokens);
+    let mut initial = AccumState::default();
+    if let [TokenTree::Group(g), next, ..] = tokens.as_slice() {
+        if g.delimiter() == Delimiter::Brace {
+            return compile_error("multicall: expected `;` after the sub-block", next.span());
+        }
+    }
+    let assignment = find_assignment(&tokens);
+    let is_assignment = assignment.is_some();
+    if let Some(eq) = assignment {
+        let lets = hoist_indices(&mut tokens[..eq]);
+        if !lets.is_empty() {
+            trace!(in initial at tokens[0].span(), "assigned index reads the item. computing it beforehand.");
+            let mut block = lets;
+            block.extend(operation(tokens, ctx));
+            return vec![TokenTree::Group(Group::new(
+                Delimiter::Brace,
+                TokenStream::from_iter(block),
+            ))];
+        }
+    }
+    if let (Some(path), [TokenTree::Ident(_), TokenTree::Group(g), ..]) =
+        (&ctx.via, tokens.as_slice())
+    {
+        if g.delimiter() == Delimiter::Parenthesis && !is_assignment {
+            trace!(in initial at tokens[0].span(), "statement is a method call. resolving it through the trait.");
+            return wrap(trait_call(path, tokens, ctx), ctx);
+        }
+    }
+    if matches!(tokens.as_slice(), [TokenTree::Ident(_), x, ..] if is_punct(x, '!')) {
+        trace!(in initial at tokens[0].span(), "statement is a macro call. marking for full replay.");
+        initial.state = State::Inserted;
+    }
+    let accum = tokens.into_iter().fold(initial, |mut accum, x| {
+        let o = x.to_string();
+        if accum.state == State::Value {
+            accum.state = State::InsertNew;
+            // `set x = (value);` prefixes the value like `set x = value;`
+            if let TokenTree::Group(ref g) = x {
+                if g.delimiter() != Delimiter::Brace {
+                    trace!(in accum at x.span(), "value of 'set' is in a group. prefixing inside.");
+                    let inner: Vec<TokenTree> = g.stream().into_iter().collect();
+                    let value = if ctx.is_destructured {
+                        inner
+                            .into_iter()
+                            .map(|x| recursive_replace(x, "#", "__multicall_item__"))
+                            .collect()
+                    } else {
+                        operand(inner)
+                    };
+                    let mut group = Group::new(g.delimiter(), TokenStream::from_iter(value));
+                    group.set_span(g.span());
+                    accum.words.push(TokenTree::Group(group));
+                    accum.state = State::Inserted;
+                    return accum;
+                }
+            }
+        }
+        // Sub-calls
+        if let Some(x) = match x {
+            TokenTree::Group(ref x)
+                if accum.state == State::InsertNew && x.delimiter() != Delimiter::Bracket =>
+            {
+                Some(x)
+            }
+            _ => None,
+        } {
+            trace!(in accum at x.span(), "found group, making sub-call:");
+            accum
+                .words
+                .extend(multicall_internal(x.stream(), true, ctx));
+            accum.state = State::Inserted;
+            trace!(in accum at x.span(), "sub-call inserted.");
+        // Call content
+        } else {
+            trace!(in accum at x.span(), "found statement. parsing...");
+            if accum.state == State::InsertNew {
+                trace!(in accum at x.span(), "detecting statement type...");
+                if o == "set" {
+                    trace!(in accum at x.span(), "statement is 'set'.");
+                    accum.state = State::Set;
+                    return accum; // dont insert
+                } else if o == "let" {
+                    trace!(in accum at x.span(), "statement is 'let'. marking for full replay.");
+                    accum.state = State::Inserted;
+                    accum.words.push(x);
+                    return accum;
+                } else if o == "exec" {
+                    trace!(in accum at x.span(), "statement is 'exec'. marking for full replay.");
+                    accum.state = State::Inserted;
+                    return accum; // dont insert
+                } else if o == "#" {
+                    trace!(in accum at x.span(), "statement starts with the item. not inserting it again.");
+                    accum.is_operation = accum.words.is_empty();
+                    accum.state = State::Inserted;
+                    accum
+                        .words
+                        .push(recursive_replace(x, "#", "__multicall_item__"));
+                    return accum;
+                } else if ctx.is_destructured {
+                    trace!(in accum at x.span(), "item is destructured. replaying statement as is.");
+                    accum.is_operation = accum.words.is_empty();
+                    accum.state = State::Inserted;
+                    accum.words.push(x);
+                    return accum;
+                }
+                trace!(in accum at x.span(), "inserting item.");
+                accum.is_operation = accum.words.is_empty();
+                let [item, dot] = item_at(x.span());
+                accum.words.push(item);
+                // an index applies to the item itself
+                if !matches!(x, TokenTree::Group(ref g) if g.delimiter() == Delimiter::Bracket) {
+                    accum.words.push(dot);
+                }
+                accum.state = State::Inserted;
+                trace!(in accum at x.span(), "done. replaying rest.");
+            }
+            if accum.state == State::Set {
+                let is_first = accum.words.iter().all(|x| x.to_string() == "let");
+                match x {
+                    TokenTree::Group(ref g)
+                        if g.delimiter() == Delimiter::Brace
+                            || (is_first && g.delimiter() != Delimiter::None) =>
+                    {
+                        accum.is_pattern = true;
+                    }
+                    _ => {}
+                }
+            }
+            if accum.state == State::Set && o == "=" {
+                trace!(in accum at x.span(), "replaying '='.");
+                if accum.is_pattern {
+                    trace!(in accum at x.span(), "target is a pattern. replaying value as is.");
+                    accum.state = State::Inserted;
+                } else {
+                    accum.state = State::Value;
+                }
+            }
+            trace!(in accum at x.span(), "replaying '{x}'");
+            accum
+                .words
+                .push(recursive_replace(x, "#", "__multicall_item__"));
+        }
+        accum
+    });
+    // Only operations that produce a value are wrapped, assignments are left alone.
+    if accum.is_operation && !is_assignment {
+        wrap(accum.words, ctx)
+    } else {
+        accum.words
+    }
+}
+
+/// Passes an operation to the `wrap` macro, if there is one.
+fn wrap(words: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    match ctx.wrap {
+        Some(ref wrap) => {
+            trace!("wrapping statement.");
+            let mut wrapped = wrap.clone();
+            wrapped.push(TokenTree::Group(Group::new(
+                Delimiter::Parenthesis,
+                TokenStream::from_iter(words),
+            )));
+            wrapped
+        }
+        None => words,
+    }
+}
+
+/// Turns `method(args)...` into `Trait::method(item, args)...` for the `via trait` directive.
+fn trait_call(path: &[TokenTree], mut tokens: Vec<TokenTree>, ctx: &Context) -> Vec<TokenTree> {
+    let rest = tokens.split_off(2);
+    let Some(TokenTree::Group(args)) = tokens.pop() else {
+        unreachable!()
+    };
+    let mut words = path.to_vec();
+    words.extend(code("::"));
+    words.extend(tokens);
+    let mut receiver = if ctx.is_mut {
+        code("&mut *__multicall_item__")
+    } else {
+        code("__multicall_item__")
+    };
+    if !args.stream().is_empty() {
+        receiver.push(TokenTree::Punct(Punct::new(',', Spacing::Alone)));
+    }
+    let mut group = Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from_iter(
+            receiver.into_iter().chain(
+                args.stream()
+                    .into_iter()
+                    .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+            ),
+        ),
+    );
+    group.set_span(args.span());
+    words.push(TokenTree::Group(group));
+    words.extend(
+        rest.into_iter()
+            .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+    );
+    words
+}
+
+/// Replaces indices that use `#` in the target of an assignment by temporaries, returning their
+/// `let`s. Otherwise, `items[#.len() - 1] = x` would borrow the item while it is borrowed mutably.
+fn hoist_indices(target: &mut [TokenTree]) -> Vec<TokenTree> {
+    let mut lets = Vec::new();
+    for (i, x) in target.iter_mut().enumerate() {
+        let TokenTree::Group(g) = x else { continue };
+        if g.delimiter() != Delimiter::Bracket || !g.stream().to_string().contains('#') {
+            continue;
+        }
+        let temp = Ident::new(&format!("__multicall_index_{i}__"), Span::call_site());
+        lets.push(TokenTree::Ident(Ident::new("let", Span::call_site())));
+        lets.push(TokenTree::Ident(temp.clone()));
+        lets.push(TokenTree::Punct(Punct::new('=', Spacing::Alone)));
+        lets.extend(
+            g.stream()
+                .into_iter()
+                .map(|x| recursive_replace(x, "#", "__multicall_item__")),
+        );
+        lets.push(TokenTree::Punct(Punct::new(';', Spacing::Alone)));
+        let mut index = Group::new(
+            Delimiter::Bracket,
+            TokenStream::from(TokenTree::Ident(temp)),
+        );
+        index.set_span(g.span());
+        *x = TokenTree::Group(index);
+    }
+    lets
+}
+
+fn recursive_replace(token: TokenTree, from: &str, to: &str) -> TokenTree {
+    match token {
+        TokenTree::Group(x) => TokenTree::Group({
+            let mut g = Group::new(
+                x.delimiter(),
+                TokenStream::from_iter(
+                    item_refs(x.stream().into_iter().collect())
+                        .into_iter()
+                        .map(|x| recursive_replace(x, from, to)),
+                ),
+            );
+            g.set_span(x.span());
+            g
+        }),
+        TokenTree::Ident(x) => TokenTree::Ident(Ident::new(
+            x.to_string().replace(from, to).as_str(),
+            x.span(),
+        )),
+        TokenTree::Punct(x) if from.len() == 1 && from.starts_with(x.as_char()) => {
+            TokenTree::Ident(Ident::new(to, x.span()))
+        }
+        x => x,
+    }
+}
+
+/// Turns `#ref` into `&#` and `#mut` into `&mut #`.
+fn item_refs(mut tokens: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut i = 0;
+    while i + 1 < tokens.len() {
+        let kind = tokens[i + 1].to_string();
+        if is_punct(&tokens[i], '#') && matches!(kind.as_str(), "ref" | "mut") {
+            trace!("found '#{kind}'. borrowing the item.");
+            let hash = tokens.remove(i);
+            let mut and = Punct::new('&', Spacing::Alone);
+            and.set_span(hash.span());
+            tokens[i] = TokenTree::Punct(and);
+            if kind == "mut" {
+                i += 1;
+                tokens.insert(i, TokenTree::Ident(Ident::new("mut", hash.span())));
+            }
+            tokens.insert(i + 1, hash);
+            i += 1;
+        }
+        i += 1;
     }
+    tokens
 }